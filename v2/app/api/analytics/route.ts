@@ -0,0 +1,17 @@
+import { NextResponse } from "next/server";
+import { getAnalytics } from "@/lib/pueue";
+
+export const runtime = "nodejs";
+export const dynamic = "force-dynamic";
+
+export async function GET() {
+  try {
+    const payload = await getAnalytics();
+    return NextResponse.json(payload);
+  } catch (error) {
+    return NextResponse.json(
+      { ok: false, error: error instanceof Error ? error.message : "Unknown error" },
+      { status: 500 }
+    );
+  }
+}
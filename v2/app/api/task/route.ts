@@ -1,4 +1,5 @@
 import { NextResponse } from "next/server";
+import { backendHeaders } from "@/lib/pueue";
 
 const BACKEND_URL = process.env.PUEUE_V2_BACKEND_URL ?? "http://127.0.0.1:9093";
 
@@ -13,7 +14,7 @@ export async function POST(request: Request) {
     }
     const response = await fetch(`${BACKEND_URL}/tasks`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: backendHeaders(),
       body: JSON.stringify(body),
     });
     const json = await response.json();
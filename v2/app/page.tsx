@@ -12,6 +12,12 @@ type ApiStatusResponse = {
   digest?: string;
 };
 
+type QueueDrain = {
+  drainMs?: number;
+  drainAt?: string;
+  unestimated: number;
+};
+
 type ApiLogResponse = {
   ok: boolean;
   log?: Record<string, unknown>;
@@ -578,6 +584,7 @@ export default function Page() {
   const [isVisible, setIsVisible] = useState(true);
   const lastDigestRef = useRef<string | null>(null);
   const taskCacheRef = useRef<Map<string, TaskCacheEntry>>(new Map());
+  const [queueDrains, setQueueDrains] = useState<Record<string, QueueDrain>>({});
 
   const openLogModal = useCallback(() => {
     setIsLogModalOpen(true);
@@ -659,6 +666,33 @@ export default function Page() {
     };
   }, [load, pollMs, isVisible]);
 
+  // ETAs only change with the state, so they're refreshed when the digest changes.
+  useEffect(() => {
+    if (!data.ok) return;
+    let active = true;
+    void (async () => {
+      try {
+        const res = await fetch("/api/analytics", { cache: "no-store" });
+        const json = (await res.json()) as { ok?: boolean; result?: { groups?: Record<string, any> } };
+        if (!active || !json.ok) return;
+        const drains: Record<string, QueueDrain> = {};
+        Object.entries(json.result?.groups ?? {}).forEach(([group, entry]) => {
+          drains[group] = {
+            drainMs: typeof entry.drain_in_secs === "number" ? entry.drain_in_secs * 1000 : undefined,
+            drainAt: entry.drain_at ?? undefined,
+            unestimated: entry.unestimated ?? 0,
+          };
+        });
+        setQueueDrains(drains);
+      } catch {
+        // The dashboard works without ETAs.
+      }
+    })();
+    return () => {
+      active = false;
+    };
+  }, [data.ok, data.digest]);
+
   useEffect(() => {
     const handler = () => setIsVisible(!document.hidden);
     document.addEventListener("visibilitychange", handler);
@@ -1464,7 +1498,23 @@ export default function Page() {
               </div>
               <div>{group.total}</div>
               <div>{group.running}</div>
-              <div>{group.queued}</div>
+              <div>
+                {group.queued}
+                {group.queued > 0 && queueDrains[group.group]?.drainMs !== undefined && (
+                  <span
+                    className="queue-drain"
+                    title={`Expected to drain at ${formatTimestamp(queueDrains[group.group].drainAt) ?? "—"}${
+                      queueDrains[group.group].unestimated > 0
+                        ? ` (${queueDrains[group.group].unestimated} tasks without estimate)`
+                        : ""
+                    }`}
+                  >
+                    {" "}
+                    · {queueDrains[group.group].unestimated > 0 ? "≥ " : ""}
+                    {formatDuration(queueDrains[group.group].drainMs)}
+                  </span>
+                )}
+              </div>
               <div>{group.paused}</div>
               <div>{group.done}</div>
               <div>{group.success}</div>
@@ -1,10 +1,18 @@
 const BACKEND_URL = process.env.PUEUE_V2_BACKEND_URL ?? "http://127.0.0.1:9093";
+const BACKEND_TOKEN = process.env.PUEUE_V2_BACKEND_TOKEN;
+
+export function backendHeaders(): Record<string, string> {
+  return {
+    "Content-Type": "application/json",
+    ...(BACKEND_TOKEN ? { Authorization: `Bearer ${BACKEND_TOKEN}` } : {}),
+  };
+}
 
 async function backendFetch(path: string, init?: RequestInit) {
   const response = await fetch(`${BACKEND_URL}${path}`, {
     ...init,
     headers: {
-      "Content-Type": "application/json",
+      ...backendHeaders(),
       ...(init?.headers ?? {}),
     },
     cache: "no-store",
@@ -22,6 +30,10 @@ export async function getStatus(): Promise<Record<string, unknown>> {
   return backendFetch("/status");
 }
 
+export async function getAnalytics(): Promise<Record<string, unknown>> {
+  return backendFetch("/analytics");
+}
+
 export async function getLog(taskId: string): Promise<unknown> {
   const payload = await backendFetch(`/logs/${taskId}`);
   return payload.log as unknown;
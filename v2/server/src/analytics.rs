@@ -0,0 +1,418 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+
+use chrono::{DateTime, Duration, Local};
+use pueue_lib::state::{GroupStatus, State};
+use pueue_lib::task::{Task, TaskResult, TaskStatus};
+use serde::Serialize;
+use serde_json::json;
+use tide::{Request, StatusCode};
+
+use crate::archive::{ArchivedSample, HistoryQuery};
+use crate::{cached_status, error_response, json_response, AppState};
+
+/// How many archived tasks are taken into account, newest first.
+const HISTORY_LIMIT: usize = 5000;
+
+/// The rolling windows, by name and length in hours.
+const WINDOWS: [(&str, i64); 3] = [("1h", 1), ("24h", 24), ("7d", 24 * 7)];
+
+/// The duration of one finished task.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub id: usize,
+    pub group: String,
+    pub label: Option<String>,
+    pub command: String,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub success: bool,
+}
+
+impl Sample {
+    pub fn from_task(task: &Task) -> Option<Self> {
+        let TaskStatus::Done {
+            start, end, result, ..
+        } = &task.status
+        else {
+            return None;
+        };
+        Some(Self {
+            id: task.id,
+            group: task.group.clone(),
+            label: task.label.clone(),
+            command: task.original_command.clone(),
+            start: *start,
+            end: *end,
+            success: matches!(result, TaskResult::Success),
+        })
+    }
+
+    /// `None` if the archive holds a malformed timestamp.
+    pub fn from_archived(task: ArchivedSample) -> Option<Self> {
+        let parse = |value: &str| {
+            DateTime::parse_from_rfc3339(value)
+                .ok()
+                .map(|time| time.with_timezone(&Local))
+        };
+        Some(Self {
+            id: task.id,
+            start: parse(&task.start)?,
+            end: parse(&task.end)?,
+            group: task.group,
+            label: task.label,
+            command: task.command,
+            success: task.success,
+        })
+    }
+
+    fn seconds(&self) -> f64 {
+        (self.end - self.start).num_milliseconds() as f64 / 1000.0
+    }
+}
+
+/// Count, mean and percentiles of a set of durations, in seconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DurationSummary {
+    pub count: usize,
+    pub mean_secs: Option<f64>,
+    pub p50_secs: Option<f64>,
+    pub p90_secs: Option<f64>,
+    pub p99_secs: Option<f64>,
+}
+
+impl DurationSummary {
+    fn new(mut durations: Vec<f64>) -> Self {
+        durations.sort_by(f64::total_cmp);
+        let mean =
+            (!durations.is_empty()).then(|| durations.iter().sum::<f64>() / durations.len() as f64);
+        Self {
+            count: durations.len(),
+            mean_secs: mean,
+            p50_secs: percentile(&durations, 50.0),
+            p90_secs: percentile(&durations, 90.0),
+            p99_secs: percentile(&durations, 99.0),
+        }
+    }
+}
+
+/// Tasks that finished within a rolling window.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WindowSummary {
+    pub success: usize,
+    pub failed: usize,
+    pub durations: DurationSummary,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GroupAnalytics {
+    pub durations: DurationSummary,
+    /// By window name, i.e. `1h`, `24h` and `7d`.
+    pub windows: BTreeMap<String, WindowSummary>,
+    pub parallel_tasks: usize,
+    pub paused: bool,
+    pub running: usize,
+    pub queued: usize,
+    /// When the last running or queued task is expected to finish. Only a lower
+    /// bound if some of the tasks couldn't be estimated.
+    pub drain_in_secs: Option<f64>,
+    pub drain_at: Option<DateTime<Local>>,
+    /// Running and queued tasks without any similar finished task to estimate from.
+    pub unestimated: usize,
+}
+
+/// The prediction for a running or queued task.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TaskEta {
+    pub id: usize,
+    pub group: String,
+    /// `running` or `queued`.
+    pub state: &'static str,
+    pub expected_secs: Option<f64>,
+    /// What the estimate is based on: `label`, `command`, `prefix` or `group`.
+    pub basis: Option<&'static str>,
+    pub start_in_secs: f64,
+    pub finish_in_secs: Option<f64>,
+    pub finish_at: Option<DateTime<Local>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Analytics {
+    /// How many finished tasks the numbers are based on.
+    pub samples: usize,
+    pub groups: BTreeMap<String, GroupAnalytics>,
+    pub labels: BTreeMap<String, DurationSummary>,
+    pub tasks: Vec<TaskEta>,
+}
+
+/// Nearest-rank percentile of sorted values.
+pub(crate) fn percentile(sorted: &[f64], percent: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// The first two words of a command, e.g. `cargo build` for `cargo build --release`.
+fn command_prefix(command: &str) -> String {
+    command
+        .split_whitespace()
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Estimate how long `task` takes from the median duration of the most similar
+/// finished tasks. Successful runs are preferred, as failures tend to end early.
+///
+/// `prefixes` holds the [`command_prefix`] of every sample.
+fn estimate(task: &Task, samples: &[Sample], prefixes: &[String]) -> Option<(f64, &'static str)> {
+    let prefix = command_prefix(&task.original_command);
+    for basis in ["label", "command", "prefix", "group"] {
+        let matching: Vec<&Sample> = samples
+            .iter()
+            .zip(prefixes)
+            .filter(|(sample, sample_prefix)| match basis {
+                "label" => task.label.is_some() && sample.label == task.label,
+                "command" => sample.command == task.original_command,
+                "prefix" => !prefix.is_empty() && **sample_prefix == prefix,
+                _ => sample.group == task.group,
+            })
+            .map(|(sample, _)| sample)
+            .collect();
+        let successful: Vec<f64> = matching
+            .iter()
+            .filter(|sample| sample.success)
+            .map(|sample| sample.seconds())
+            .collect();
+        let mut durations = if successful.is_empty() {
+            matching.iter().map(|sample| sample.seconds()).collect()
+        } else {
+            successful
+        };
+        durations.sort_by(f64::total_cmp);
+        if let Some(median) = percentile(&durations, 50.0) {
+            return Some((median, basis));
+        }
+    }
+    None
+}
+
+/// Duration statistics and queue predictions for a daemon.
+///
+/// `history` holds finished tasks that may no longer be in `state`, e.g. from the
+/// archive. Tasks that appear in both are only counted once.
+pub fn analyze(state: &State, history: &[Sample], now: DateTime<Local>) -> Analytics {
+    let mut seen = HashSet::new();
+    let samples: Vec<Sample> = state
+        .tasks
+        .values()
+        .filter_map(Sample::from_task)
+        .chain(history.iter().cloned())
+        .filter(|sample| seen.insert((sample.id, sample.start.timestamp_millis())))
+        .collect();
+    let prefixes: Vec<String> = samples
+        .iter()
+        .map(|sample| command_prefix(&sample.command))
+        .collect();
+
+    let mut labels: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for sample in &samples {
+        if let Some(label) = &sample.label {
+            labels
+                .entry(label.clone())
+                .or_default()
+                .push(sample.seconds());
+        }
+    }
+
+    let mut group_names: Vec<&String> = state.groups.keys().collect();
+    for task in state.tasks.values() {
+        if !group_names.contains(&&task.group) {
+            group_names.push(&task.group);
+        }
+    }
+
+    let mut groups = BTreeMap::new();
+    let mut tasks = Vec::new();
+    for name in group_names {
+        let in_group: Vec<&Sample> = samples.iter().filter(|s| &s.group == name).collect();
+        let windows = WINDOWS
+            .iter()
+            .map(|(window, hours)| {
+                let since = now - Duration::hours(*hours);
+                let recent: Vec<&&Sample> = in_group.iter().filter(|s| s.end >= since).collect();
+                let summary = WindowSummary {
+                    success: recent.iter().filter(|s| s.success).count(),
+                    failed: recent.iter().filter(|s| !s.success).count(),
+                    durations: DurationSummary::new(recent.iter().map(|s| s.seconds()).collect()),
+                };
+                (window.to_string(), summary)
+            })
+            .collect();
+
+        let group = state.groups.get(name);
+        let parallel_tasks = group.map_or(1, |group| group.parallel_tasks);
+        let mut etas = predict(state, name, parallel_tasks, &samples, &prefixes, now);
+        let running = etas.iter().filter(|eta| eta.state == "running").count();
+        let unestimated = etas
+            .iter()
+            .filter(|eta| eta.expected_secs.is_none())
+            .count();
+        let drain_in_secs = etas
+            .iter()
+            .filter_map(|eta| eta.finish_in_secs)
+            .max_by(f64::total_cmp);
+
+        groups.insert(
+            name.clone(),
+            GroupAnalytics {
+                durations: DurationSummary::new(in_group.iter().map(|s| s.seconds()).collect()),
+                windows,
+                parallel_tasks,
+                paused: group.is_some_and(|group| group.status == GroupStatus::Paused),
+                running,
+                queued: etas.len() - running,
+                drain_in_secs,
+                drain_at: drain_in_secs.map(|secs| at(now, secs)),
+                unestimated,
+            },
+        );
+        tasks.append(&mut etas);
+    }
+
+    Analytics {
+        samples: samples.len(),
+        groups,
+        labels: labels
+            .into_iter()
+            .map(|(label, durations)| (label, DurationSummary::new(durations)))
+            .collect(),
+        tasks,
+    }
+}
+
+/// Simulate the group's queue: running tasks keep their slots for their remaining
+/// time and queued tasks start in the daemon's order, highest priority first, as
+/// soon as one of the `parallel_tasks` slots is free. Zero slots means unlimited.
+///
+/// Dependencies and paused groups aren't taken into account, so this answers
+/// "when will the queue drain if nothing gets in the way?".
+fn predict(
+    state: &State,
+    group: &str,
+    parallel_tasks: usize,
+    samples: &[Sample],
+    prefixes: &[String],
+    now: DateTime<Local>,
+) -> Vec<TaskEta> {
+    let mut running = Vec::new();
+    let mut queued = Vec::new();
+    for task in state.tasks.values().filter(|task| task.group == group) {
+        match &task.status {
+            // Paused tasks keep their slot, just like in the daemon.
+            TaskStatus::Running { start, .. } | TaskStatus::Paused { start, .. } => {
+                running.push((task, *start))
+            }
+            TaskStatus::Queued { .. } => queued.push(task),
+            _ => {}
+        }
+    }
+    queued.sort_by_key(|task| (Reverse(task.priority), task.id));
+
+    // Finish times of the occupied slots, in milliseconds from now.
+    let mut slots = BinaryHeap::new();
+    let mut etas = Vec::new();
+    for (task, start) in running {
+        let estimate = estimate(task, samples, prefixes);
+        let elapsed = (now - start).num_milliseconds() as f64 / 1000.0;
+        // Overdue tasks are expected to finish any moment now.
+        let remaining = estimate.map(|(expected, _)| (expected - elapsed).max(0.0));
+        slots.push(Reverse(millis(remaining.unwrap_or(0.0))));
+        etas.push(eta(task, "running", estimate, 0.0, remaining, now));
+    }
+
+    let mut current = 0;
+    for task in queued {
+        if parallel_tasks > 0 {
+            while slots.len() >= parallel_tasks {
+                let Some(Reverse(free)) = slots.pop() else {
+                    break;
+                };
+                current = current.max(free);
+            }
+        }
+        let estimate = estimate(task, samples, prefixes);
+        let start_in = current as f64 / 1000.0;
+        let finish_in = estimate.map(|(expected, _)| start_in + expected);
+        slots.push(Reverse(millis(finish_in.unwrap_or(start_in))));
+        etas.push(eta(task, "queued", estimate, start_in, finish_in, now));
+    }
+    etas
+}
+
+fn eta(
+    task: &Task,
+    state: &'static str,
+    estimate: Option<(f64, &'static str)>,
+    start_in_secs: f64,
+    finish_in_secs: Option<f64>,
+    now: DateTime<Local>,
+) -> TaskEta {
+    TaskEta {
+        id: task.id,
+        group: task.group.clone(),
+        state,
+        expected_secs: estimate.map(|(expected, _)| expected),
+        basis: estimate.map(|(_, basis)| basis),
+        start_in_secs,
+        finish_in_secs,
+        finish_at: finish_in_secs.map(|secs| at(now, secs)),
+    }
+}
+
+fn millis(seconds: f64) -> i64 {
+    (seconds * 1000.0).round() as i64
+}
+
+fn at(now: DateTime<Local>, seconds: f64) -> DateTime<Local> {
+    now + Duration::milliseconds(millis(seconds))
+}
+
+/// `GET /analytics`
+pub(crate) async fn analytics_handler(req: Request<AppState>) -> tide::Result {
+    let state = req.state();
+    let entry = match cached_status(state).await {
+        Ok((entry, _)) => entry,
+        Err(error) => return error_response(error),
+    };
+
+    let archive = state.archive.clone();
+    let query = HistoryQuery {
+        daemon: Some(state.name.clone()),
+        limit: Some(HISTORY_LIMIT),
+        ..Default::default()
+    };
+    // Going through thousands of archived tasks takes a while, so keep it off the executor.
+    let result = async_std::task::spawn_blocking(move || -> anyhow::Result<Analytics> {
+        let mut history = Vec::new();
+        if let Some(archive) = archive {
+            let tasks = archive.query_samples(&query)?;
+            history = tasks.into_iter().filter_map(Sample::from_archived).collect();
+        }
+        Ok(analyze(&entry.payload, &history, Local::now()))
+    })
+    .await;
+
+    match result {
+        Ok(analytics) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "result": analytics,
+            }),
+        ),
+        Err(error) => error_response(error),
+    }
+}
@@ -0,0 +1,423 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use log::{debug, warn};
+use pueue_lib::task::{Task, TaskResult, TaskStatus};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tide::{Request, StatusCode};
+
+use crate::error::ApiError;
+use crate::events::{EventHub, StatusEvent};
+use crate::{cached_status, json_response, AppState};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tasks (
+    daemon TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    command TEXT NOT NULL,
+    path TEXT NOT NULL,
+    label TEXT,
+    task_group TEXT NOT NULL,
+    priority INTEGER NOT NULL,
+    enqueued_at TEXT NOT NULL,
+    start TEXT NOT NULL,
+    end TEXT NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    result TEXT NOT NULL,
+    success INTEGER NOT NULL,
+    output TEXT,
+    archived_at TEXT NOT NULL,
+    PRIMARY KEY (daemon, id, start)
+);
+CREATE INDEX IF NOT EXISTS tasks_end ON tasks (end);
+";
+
+/// A finished task, as stored in the archive.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedTask {
+    pub daemon: String,
+    pub id: usize,
+    pub command: String,
+    pub path: String,
+    pub label: Option<String>,
+    pub group: String,
+    pub priority: i32,
+    /// UTC RFC3339 timestamps, so that they sort and compare as text.
+    pub enqueued_at: String,
+    pub start: String,
+    pub end: String,
+    pub duration_ms: i64,
+    pub result: String,
+    pub success: bool,
+    /// The last lines of the task's output.
+    pub output: Option<String>,
+}
+
+/// The columns of an archived task that duration statistics are based on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchivedSample {
+    pub id: usize,
+    pub command: String,
+    pub label: Option<String>,
+    pub group: String,
+    /// UTC RFC3339 timestamps, like in [`ArchivedTask`].
+    pub start: String,
+    pub end: String,
+    pub success: bool,
+}
+
+impl ArchivedTask {
+    /// `None` if the task hasn't finished yet.
+    pub fn from_task(daemon: &str, task: &Task, output: Option<String>) -> Option<Self> {
+        let TaskStatus::Done {
+            enqueued_at,
+            start,
+            end,
+            result,
+        } = &task.status
+        else {
+            return None;
+        };
+        Some(Self {
+            daemon: daemon.to_string(),
+            id: task.id,
+            command: task.original_command.clone(),
+            path: task.path.to_string_lossy().to_string(),
+            label: task.label.clone(),
+            group: task.group.clone(),
+            priority: task.priority,
+            enqueued_at: timestamp(enqueued_at),
+            start: timestamp(start),
+            end: timestamp(end),
+            duration_ms: (*end - *start).num_milliseconds(),
+            result: result.to_string(),
+            success: matches!(result, TaskResult::Success),
+            output,
+        })
+    }
+}
+
+/// Filters of `GET /history`. All of them are optional.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    pub daemon: Option<String>,
+    pub group: Option<String>,
+    /// `success`, `failed` or a result as shown by pueue, e.g. `Killed`.
+    pub result: Option<String>,
+    /// Only tasks whose command contains this.
+    pub command: Option<String>,
+    pub label: Option<String>,
+    /// RFC3339 timestamps, matched against the end of the task.
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// The maximum number of tasks, newest first. Defaults to 100.
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+/// File-backed history of finished tasks, which outlives `pueue clean` and `pueue remove`.
+pub struct Archive {
+    connection: Mutex<Connection>,
+    log_lines: usize,
+}
+
+impl Archive {
+    pub fn open(path: &Path, log_lines: usize) -> Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open archive {}", path.display()))?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("Failed to set up the archive")?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            log_lines,
+        })
+    }
+
+    /// Uses `path` if set, `PUEUE_WEBUI_ARCHIVE` otherwise. `None` disables the archive.
+    pub fn from_env(path: Option<PathBuf>) -> Result<Option<Self>> {
+        let path = path.or_else(|| {
+            std::env::var("PUEUE_WEBUI_ARCHIVE")
+                .ok()
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        });
+        let log_lines = std::env::var("PUEUE_WEBUI_ARCHIVE_LOG_LINES")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(100);
+        path.map(|path| Self::open(&path, log_lines)).transpose()
+    }
+
+    /// Whether this run of the task has already been archived.
+    pub fn contains(&self, daemon: &str, id: usize, start: &str) -> Result<bool> {
+        let connection = self.lock();
+        let found = connection
+            .query_row(
+                "SELECT 1 FROM tasks WHERE daemon = ?1 AND id = ?2 AND start = ?3",
+                params![daemon, id as i64, start],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Store a task. Returns `false` if it has already been archived.
+    pub fn insert(&self, task: &ArchivedTask) -> Result<bool> {
+        let connection = self.lock();
+        let inserted = connection.execute(
+            "INSERT OR IGNORE INTO tasks (daemon, id, command, path, label, task_group, priority,
+                enqueued_at, start, end, duration_ms, result, success, output, archived_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            params![
+                task.daemon,
+                task.id as i64,
+                task.command,
+                task.path,
+                task.label,
+                task.group,
+                task.priority,
+                task.enqueued_at,
+                task.start,
+                task.end,
+                task.duration_ms,
+                task.result,
+                task.success,
+                task.output,
+                timestamp(&Local::now()),
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// The archived tasks matching `query`, most recently finished first.
+    pub fn query(&self, query: &HistoryQuery) -> Result<Vec<ArchivedTask>> {
+        let columns = "daemon, id, command, path, label, task_group, priority, enqueued_at,
+                start, end, duration_ms, result, success, output";
+        self.select(query, columns, |row| {
+            Ok(ArchivedTask {
+                daemon: row.get(0)?,
+                id: row.get::<_, i64>(1)? as usize,
+                command: row.get(2)?,
+                path: row.get(3)?,
+                label: row.get(4)?,
+                group: row.get(5)?,
+                priority: row.get(6)?,
+                enqueued_at: row.get(7)?,
+                start: row.get(8)?,
+                end: row.get(9)?,
+                duration_ms: row.get(10)?,
+                result: row.get(11)?,
+                success: row.get(12)?,
+                output: row.get(13)?,
+            })
+        })
+    }
+
+    /// Like [`Archive::query`], but without the columns duration statistics don't need,
+    /// most notably the output.
+    pub fn query_samples(&self, query: &HistoryQuery) -> Result<Vec<ArchivedSample>> {
+        let columns = "id, command, label, task_group, start, end, success";
+        self.select(query, columns, |row| {
+            Ok(ArchivedSample {
+                id: row.get::<_, i64>(0)? as usize,
+                command: row.get(1)?,
+                label: row.get(2)?,
+                group: row.get(3)?,
+                start: row.get(4)?,
+                end: row.get(5)?,
+                success: row.get(6)?,
+            })
+        })
+    }
+
+    /// Read `columns` of the tasks matching `query`, most recently finished first.
+    fn select<T>(
+        &self,
+        query: &HistoryQuery,
+        columns: &str,
+        read_row: impl FnMut(&rusqlite::Row) -> rusqlite::Result<T>,
+    ) -> Result<Vec<T>> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<rusqlite::types::Value> = Vec::new();
+        // Conditions refer to their value as `{}`.
+        let mut filter = |condition: &str, value: rusqlite::types::Value| {
+            conditions.push(condition.replace("{}", &format!("?{}", values.len() + 1)));
+            values.push(value);
+        };
+
+        if let Some(daemon) = query.daemon.clone() {
+            filter("daemon = {}", daemon.into());
+        }
+        if let Some(group) = query.group.clone() {
+            filter("task_group = {}", group.into());
+        }
+        match query.result.as_deref().map(str::to_lowercase) {
+            None => {}
+            Some(result) if result == "success" => filter("success = {}", 1.into()),
+            Some(result) if result == "failed" => filter("success = {}", 0.into()),
+            Some(result) => filter("lower(result) = {}", result.into()),
+        }
+        if let Some(command) = query.command.clone() {
+            filter("instr(command, {}) > 0", command.into());
+        }
+        if let Some(label) = query.label.clone() {
+            filter("label = {}", label.into());
+        }
+        if let Some(since) = query.since.as_deref() {
+            filter("end >= {}", parse_timestamp(since)?.into());
+        }
+        if let Some(until) = query.until.as_deref() {
+            filter("end <= {}", parse_timestamp(until)?.into());
+        }
+
+        let mut sql = format!("SELECT {columns} FROM tasks");
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(&format!(
+            " ORDER BY end DESC LIMIT {} OFFSET {}",
+            query.limit.unwrap_or(100),
+            query.offset.unwrap_or(0)
+        ));
+
+        let connection = self.lock();
+        let mut statement = connection.prepare(&sql)?;
+        let rows = statement
+            .query_map(rusqlite::params_from_iter(values), read_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Archive the tasks of a daemon as they finish.
+///
+/// This subscribes to the daemon's [`EventHub`], so it shares the status polling
+/// with `/events` and keeps it running for as long as the server runs.
+pub(crate) fn watch(archive: Arc<Archive>, daemon: String, state: AppState) {
+    async_std::task::spawn(async move {
+        loop {
+            let events = EventHub::subscribe(&state.events, &state);
+            while let Ok(event) = events.recv().await {
+                let tasks: Vec<Task> = match event {
+                    StatusEvent::Snapshot { status } => status.tasks.into_values().collect(),
+                    // Tasks that finish within one poll interval show up as done right away.
+                    StatusEvent::TaskAdded { task, .. } => vec![task],
+                    StatusEvent::TaskStatusChanged {
+                        id,
+                        status: TaskStatus::Done { .. },
+                        ..
+                    } => match cached_status(&state).await {
+                        Ok((entry, _)) => {
+                            entry.payload.tasks.get(&id).cloned().into_iter().collect()
+                        }
+                        Err(error) => {
+                            debug!("Failed to get finished task {id} for the archive: {error}");
+                            Vec::new()
+                        }
+                    },
+                    _ => Vec::new(),
+                };
+                for task in tasks {
+                    if let Err(error) = archive_task(&archive, &daemon, &state, &task).await {
+                        warn!("Failed to archive task {} of {daemon}: {error:#}", task.id);
+                    }
+                }
+            }
+            // The hub drops subscribers that fall behind. Subscribing again starts
+            // with a snapshot, so nothing that finished in between is missed.
+            async_std::task::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+async fn archive_task(
+    archive: &Arc<Archive>,
+    daemon: &str,
+    state: &AppState,
+    task: &Task,
+) -> Result<()> {
+    let TaskStatus::Done { start, .. } = &task.status else {
+        return Ok(());
+    };
+    let start = timestamp(start);
+    let (check, name) = (archive.clone(), daemon.to_string());
+    let id = task.id;
+    let archived =
+        async_std::task::spawn_blocking(move || check.contains(&name, id, &start)).await?;
+    if archived {
+        return Ok(());
+    }
+
+    let output = match state.backend.logs(task.id, Some(archive.log_lines)).await {
+        Ok(logs) => log_output(&logs, task.id),
+        Err(error) => {
+            debug!("Archiving task {} without its log: {error}", task.id);
+            None
+        }
+    };
+    let Some(record) = ArchivedTask::from_task(daemon, task, output) else {
+        return Ok(());
+    };
+    let archive = archive.clone();
+    async_std::task::spawn_blocking(move || archive.insert(&record)).await?;
+    Ok(())
+}
+
+/// The output in the responses of [`crate::PueueBackend::logs`], which differ
+/// between the protocol (`output`), the CLI (keyed by id) and the fake backend (`stdout`).
+fn log_output(logs: &serde_json::Value, task_id: usize) -> Option<String> {
+    let log = logs.get(task_id.to_string()).unwrap_or(logs);
+    ["output", "stdout"]
+        .iter()
+        .find_map(|key| log.get(*key).and_then(|value| value.as_str()))
+        .map(str::to_string)
+}
+
+/// `GET /history`
+pub(crate) async fn history_handler(archive: Arc<Archive>, req: Request<AppState>) -> tide::Result {
+    let query: HistoryQuery = req.query()?;
+    let result = async_std::task::spawn_blocking(move || archive.query(&query)).await;
+    match result {
+        Ok(tasks) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "result": tasks,
+            }),
+        ),
+        Err(error) => Ok(ApiError::from(error).response()),
+    }
+}
+
+fn timestamp(time: &DateTime<Local>) -> String {
+    time.with_timezone(&Utc)
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn parse_timestamp(value: &str) -> Result<String> {
+    let time = DateTime::parse_from_rfc3339(value).map_err(|_| {
+        ApiError::BadRequest(format!("Invalid timestamp {value}, expected RFC3339"))
+    })?;
+    Ok(time
+        .with_timezone(&Utc)
+        .to_rfc3339_opts(SecondsFormat::Millis, true))
+}
@@ -0,0 +1,325 @@
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, Local};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tide::http::Method;
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::auth::Identity;
+use crate::error::{response_error, ApiError};
+use crate::{
+    error_response, is_secret_env, json_response, mask_json_envs, peek_json, AppState,
+    MASKED_VALUE,
+};
+
+/// One recorded request.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// RFC3339 timestamp of when the request has been answered.
+    pub timestamp: String,
+    /// The authenticated user, `None` if authentication is disabled.
+    pub user: Option<String>,
+    pub peer: Option<String>,
+    pub method: String,
+    pub route: String,
+    /// The JSON body of the request, with secret values masked.
+    pub params: Value,
+    pub status: u16,
+    pub ok: bool,
+    /// The `Success` or `Failure` text of the daemon, or the error of the web server.
+    pub message: Option<String>,
+}
+
+impl AuditEntry {
+    /// Whether the request targeted the given task, either via its route or its body.
+    fn touches_task(&self, task_id: usize) -> bool {
+        let segments: Vec<&str> = self.route.trim_matches('/').split('/').collect();
+        let in_route = segments
+            .windows(2)
+            .any(|pair| pair[0] == "task" && pair[1].parse() == Ok(task_id));
+        let in_body = ["task_id_1", "task_id_2"]
+            .iter()
+            .any(|key| self.params[key].as_u64() == Some(task_id as u64))
+            || self.params["selection"]["ids"]
+                .as_array()
+                .is_some_and(|ids| ids.iter().any(|id| id.as_u64() == Some(task_id as u64)));
+        in_route || in_body
+    }
+}
+
+/// Filters of `GET /audit`. All of them are optional.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub user: Option<String>,
+    /// Only entries whose route contains this, e.g. `/groups` or `/env/`.
+    pub route: Option<String>,
+    pub method: Option<String>,
+    pub task: Option<usize>,
+    pub ok: Option<bool>,
+    /// RFC3339 timestamps limiting the time range.
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// The maximum number of entries, newest first. Defaults to 100.
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.user.as_ref().is_none_or(|user| entry.user.as_ref() == Some(user))
+            && self.route.as_ref().is_none_or(|route| entry.route.contains(route.as_str()))
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| entry.method.eq_ignore_ascii_case(method))
+            && self.task.is_none_or(|task| entry.touches_task(task))
+            && self.ok.is_none_or(|ok| entry.ok == ok)
+    }
+}
+
+/// An append-only log of all mutating requests, stored as JSON lines.
+///
+/// Once the file grows beyond `max_bytes`, it's moved to `<path>.1`, shifting older
+/// files up to `<path>.<max_files>` and deleting anything beyond.
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf, max_bytes: u64, max_files: usize) -> Self {
+        Self {
+            path,
+            max_bytes,
+            max_files,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Uses `path` if set, `PUEUE_WEBUI_AUDIT_LOG` otherwise. `None` disables auditing.
+    pub fn from_env(path: Option<PathBuf>) -> Option<Self> {
+        let path = path.or_else(|| {
+            std::env::var("PUEUE_WEBUI_AUDIT_LOG")
+                .ok()
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        })?;
+        let max_bytes = std::env::var("PUEUE_WEBUI_AUDIT_MAX_BYTES")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(10 * 1024 * 1024);
+        let max_files = std::env::var("PUEUE_WEBUI_AUDIT_MAX_FILES")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(5);
+        Some(Self::new(path, max_bytes, max_files))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let size = std::fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open audit log {}", self.path.display()))?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// The newest entries matching `query`, newest first.
+    pub fn read(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+        let since = query.since.as_deref().map(parse_timestamp).transpose()?;
+        let until = query.until.as_deref().map(parse_timestamp).transpose()?;
+        let limit = query.limit.unwrap_or(100);
+
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let mut entries = Vec::new();
+        // Oldest file first, so the entries end up in chronological order.
+        let files = (1..=self.max_files)
+            .rev()
+            .map(|index| self.rotated(index))
+            .chain(std::iter::once(self.path.clone()));
+        for path in files {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error.into()),
+            };
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                    continue;
+                };
+                let timestamp = DateTime::parse_from_rfc3339(&entry.timestamp).ok();
+                let in_range = since.is_none_or(|since| timestamp.is_some_and(|time| time >= since))
+                    && until.is_none_or(|until| timestamp.is_some_and(|time| time <= until));
+                if in_range && query.matches(&entry) {
+                    entries.push(entry);
+                }
+            }
+        }
+        entries.reverse();
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    fn rotate(&self) -> Result<()> {
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))?;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+}
+
+fn parse_timestamp(value: &str) -> Result<DateTime<FixedOffset>> {
+    let time = DateTime::parse_from_rfc3339(value).map_err(|_| {
+        ApiError::BadRequest(format!("Invalid timestamp {value}, expected RFC3339"))
+    })?;
+    Ok(time)
+}
+
+/// Records every request that changes something, including the rejected ones.
+pub(crate) struct AuditMiddleware {
+    log: Arc<AuditLog>,
+}
+
+impl AuditMiddleware {
+    pub(crate) fn new(log: Arc<AuditLog>) -> Self {
+        Self { log }
+    }
+}
+
+#[async_trait]
+impl Middleware<AppState> for AuditMiddleware {
+    async fn handle(&self, mut req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+        let route = req.url().path().to_string();
+        // Opening the editor locks the task, so it's recorded even though it's a GET.
+        let mutating = !matches!(req.method(), Method::Get | Method::Head) || route.ends_with("/edit");
+        if !mutating {
+            return Ok(next.run(req).await);
+        }
+
+        let user = req.ext::<Identity>().map(|identity| identity.user.clone());
+        let peer = req.peer_addr().map(str::to_string);
+        let method = req.method().to_string();
+        let mut params = peek_json::<Value>(&mut req).await?.unwrap_or(Value::Null);
+        mask_secrets(&route, &mut params);
+
+        let mut res = next.run(req).await;
+        // ErrorBody runs after this and only then sets the status of errors.
+        let (status, message) = match response_error(&res) {
+            Some((status, error)) => (status, Some(error.message().to_string())),
+            None => {
+                let content_type = res.content_type();
+                let bytes = res.take_body().into_bytes().await?;
+                let body: Option<Value> = serde_json::from_slice(&bytes).ok();
+                res.set_body(bytes);
+                if let Some(content_type) = content_type {
+                    res.set_content_type(content_type);
+                }
+                (res.status(), body.and_then(|body| response_message(&body)))
+            }
+        };
+
+        let entry = AuditEntry {
+            timestamp: Local::now().to_rfc3339(),
+            user,
+            peer,
+            method,
+            route,
+            params,
+            status: status as u16,
+            ok: status.is_success(),
+            message,
+        };
+        let log = self.log.clone();
+        let written = async_std::task::spawn_blocking(move || log.append(&entry)).await;
+        if let Err(error) = written {
+            warn!("Failed to write audit log: {error:#}");
+        }
+        Ok(res)
+    }
+}
+
+/// `GET /audit`
+pub(crate) async fn audit_handler(log: Arc<AuditLog>, req: Request<AppState>) -> tide::Result {
+    let query: AuditQuery = req.query()?;
+    let result = async_std::task::spawn_blocking(move || log.read(&query)).await;
+    match result {
+        Ok(entries) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "result": entries,
+            }),
+        ),
+        // Invalid timestamps are bad requests, anything else failed to read the log.
+        Err(error) => error_response(error),
+    }
+}
+
+/// The daemon's answer, or the error, of a JSON response.
+fn response_message(body: &Value) -> Option<String> {
+    let text = |value: &Value| match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    };
+    match body.get("error") {
+        Some(error) => text(error),
+        None => body
+            .get("result")
+            .and_then(|result| result.get("message").or(Some(result)))
+            .and_then(text),
+    }
+}
+
+/// Never write the values of secret environment variables to disk.
+fn mask_secrets(route: &str, params: &mut Value) {
+    let segments: Vec<&str> = route.trim_matches('/').split('/').collect();
+    if let [.., "env", key] = segments.as_slice() {
+        if is_secret_env(key) {
+            if let Some(value) = params.get_mut("value") {
+                *value = Value::String(MASKED_VALUE.to_string());
+            }
+        }
+    }
+    mask_json_envs(params);
+}
@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use blake2::{Blake2s256, Digest};
+use log::warn;
+use tide::http::auth::{AuthenticationScheme, Authorization, BasicAuth};
+use tide::{Middleware, Next, Request};
+
+use crate::error::ApiError;
+
+/// Routes that are reachable without credentials.
+const PUBLIC_PATHS: [&str; 1] = ["/health"];
+
+/// The authenticated user of a request, stored in the request's extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub user: String,
+    /// The provider that authenticated the request, e.g. `token` or `basic`.
+    pub method: &'static str,
+}
+
+/// A way of authenticating requests.
+pub trait AuthProvider: Send + Sync {
+    /// Short name, used as [`Identity::method`].
+    fn name(&self) -> &'static str;
+
+    /// The identity of the request, or `None` if it lacks valid credentials for this provider.
+    fn authenticate(&self, headers: &tide::http::Headers, peer_addr: Option<&str>)
+        -> Option<Identity>;
+
+    /// The `WWW-Authenticate` challenge to send along with a 401, if any.
+    fn challenge(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Static bearer tokens, read from a file with one token per line.
+pub struct TokenAuth {
+    tokens: Vec<String>,
+}
+
+impl TokenAuth {
+    pub fn new(tokens: Vec<String>) -> Self {
+        Self { tokens }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read token file {}", path.display()))?;
+        let tokens: Vec<String> = content_lines(&content).map(str::to_string).collect();
+        if tokens.is_empty() {
+            bail!("Token file {} doesn't contain any tokens", path.display());
+        }
+        Ok(Self::new(tokens))
+    }
+}
+
+impl AuthProvider for TokenAuth {
+    fn name(&self) -> &'static str {
+        "token"
+    }
+
+    fn authenticate(&self, headers: &tide::http::Headers, _: Option<&str>) -> Option<Identity> {
+        let auth = Authorization::from_headers(headers).ok()??;
+        if auth.scheme() != AuthenticationScheme::Bearer {
+            return None;
+        }
+        let credentials = auth.credentials().as_bytes();
+        // Tokens are anonymous, so they're only told apart by their position in the file.
+        self.tokens
+            .iter()
+            .position(|token| constant_time_eq(token.as_bytes(), credentials))
+            .map(|index| Identity {
+                user: format!("token-{}", index + 1),
+                method: self.name(),
+            })
+    }
+}
+
+/// HTTP basic auth against an htpasswd style file of `user:hash` lines.
+///
+/// Both bcrypt (`htpasswd -B`) and argon2 (PHC string) hashes are supported.
+/// Both are slow by design, so credentials that passed once are remembered by their
+/// digest and aren't checked against the hash again.
+pub struct BasicAuthProvider {
+    users: HashMap<String, String>,
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl BasicAuthProvider {
+    pub fn new(users: HashMap<String, String>) -> Self {
+        Self {
+            users,
+            verified: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read password file {}", path.display()))?;
+        let mut users = HashMap::new();
+        for line in content_lines(&content) {
+            let Some((user, hash)) = line.split_once(':') else {
+                bail!("Invalid line in {}, expected user:hash", path.display());
+            };
+            if !(hash.starts_with("$2") || hash.starts_with("$argon2")) {
+                bail!("Unsupported password hash for user {user}, use bcrypt or argon2");
+            }
+            users.insert(user.to_string(), hash.to_string());
+        }
+        Ok(Self::new(users))
+    }
+}
+
+impl AuthProvider for BasicAuthProvider {
+    fn name(&self) -> &'static str {
+        "basic"
+    }
+
+    fn authenticate(&self, headers: &tide::http::Headers, _: Option<&str>) -> Option<Identity> {
+        let credentials = BasicAuth::from_headers(headers).ok()??;
+        let hash = self.users.get(credentials.username())?;
+        // User names can't contain a colon, so this is unambiguous.
+        let digest: [u8; 32] = Blake2s256::new()
+            .chain_update(credentials.username())
+            .chain_update(":")
+            .chain_update(credentials.password())
+            .finalize()
+            .into();
+        let mut verified = self.verified.lock().unwrap_or_else(|err| err.into_inner());
+        let valid = verified.contains(&digest) || verify_password(credentials.password(), hash);
+        if valid {
+            verified.insert(digest);
+        }
+        valid.then(|| Identity {
+            user: credentials.username().to_string(),
+            method: self.name(),
+        })
+    }
+
+    fn challenge(&self) -> Option<String> {
+        Some("Basic realm=\"pueue-webui\"".to_string())
+    }
+}
+
+/// Trust the user name a reverse proxy put into a header, e.g. `X-Forwarded-User`.
+///
+/// The header is only honored for requests coming from one of the trusted proxies,
+/// as anybody else could simply set it themselves.
+pub struct ProxyAuth {
+    header: String,
+    trusted: Vec<IpAddr>,
+}
+
+impl ProxyAuth {
+    pub fn new(header: String, trusted: Vec<IpAddr>) -> Self {
+        Self { header, trusted }
+    }
+}
+
+impl AuthProvider for ProxyAuth {
+    fn name(&self) -> &'static str {
+        "proxy"
+    }
+
+    fn authenticate(&self, headers: &tide::http::Headers, peer_addr: Option<&str>) -> Option<Identity> {
+        let peer = peer_ip(peer_addr?)?;
+        if !self.trusted.contains(&peer) {
+            return None;
+        }
+        let user = headers.get(self.header.as_str())?.last().as_str().trim();
+        (!user.is_empty()).then(|| Identity {
+            user: user.to_string(),
+            method: self.name(),
+        })
+    }
+}
+
+/// Rejects requests that none of the configured providers can authenticate.
+pub struct AuthMiddleware {
+    providers: Vec<Box<dyn AuthProvider>>,
+}
+
+impl AuthMiddleware {
+    pub fn new(providers: Vec<Box<dyn AuthProvider>>) -> Self {
+        Self { providers }
+    }
+
+    /// Build the middleware from the given settings. `None` if no provider is configured.
+    pub fn from_config(config: &AuthConfig) -> Result<Option<Self>> {
+        let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+        if let Some(path) = config.token_file.as_ref() {
+            providers.push(Box::new(TokenAuth::from_file(path)?));
+        }
+        if let Some(path) = config.htpasswd_file.as_ref() {
+            providers.push(Box::new(BasicAuthProvider::from_file(path)?));
+        }
+        if let Some(header) = config.proxy_header.clone() {
+            // Nothing is trusted by default, as any local process could set the header otherwise.
+            if config.trusted_proxies.is_empty() {
+                bail!("The proxy header needs at least one trusted proxy address");
+            }
+            let trusted = config
+                .trusted_proxies
+                .iter()
+                .map(|ip| ip.parse().with_context(|| format!("Invalid proxy address {ip}")))
+                .collect::<Result<_>>()?;
+            providers.push(Box::new(ProxyAuth::new(header, trusted)));
+        }
+
+        if providers.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self::new(providers)))
+    }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AuthMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if PUBLIC_PATHS.contains(&req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+
+        let headers: &tide::http::Headers = req.as_ref();
+        let identity = self
+            .providers
+            .iter()
+            .find_map(|provider| provider.authenticate(headers, req.peer_addr()));
+        match identity {
+            Some(identity) => {
+                req.set_ext(identity);
+                Ok(next.run(req).await)
+            }
+            None => {
+                let mut response = ApiError::Unauthorized("Authentication required".to_string()).response();
+                if let Some(challenge) = self.providers.iter().find_map(|p| p.challenge()) {
+                    response.insert_header("WWW-Authenticate", challenge);
+                }
+                Ok(response)
+            }
+        }
+    }
+}
+
+/// Where the credentials of the auth providers come from.
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+    pub token_file: Option<PathBuf>,
+    pub htpasswd_file: Option<PathBuf>,
+    pub proxy_header: Option<String>,
+    pub trusted_proxies: Vec<String>,
+}
+
+impl AuthConfig {
+    /// Fill everything that hasn't been set explicitly from the environment.
+    pub fn with_env_defaults(mut self) -> Self {
+        let env = |key: &str| std::env::var(key).ok().filter(|value| !value.is_empty());
+        self.token_file = self
+            .token_file
+            .or_else(|| env("PUEUE_WEBUI_AUTH_TOKEN_FILE").map(PathBuf::from));
+        self.htpasswd_file = self
+            .htpasswd_file
+            .or_else(|| env("PUEUE_WEBUI_AUTH_HTPASSWD").map(PathBuf::from));
+        self.proxy_header = self
+            .proxy_header
+            .or_else(|| env("PUEUE_WEBUI_AUTH_PROXY_HEADER"));
+        if self.trusted_proxies.is_empty() {
+            self.trusted_proxies = env("PUEUE_WEBUI_AUTH_TRUSTED_PROXIES")
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(|item| item.trim().to_string())
+                        .filter(|item| !item.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default();
+        }
+        self
+    }
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        use argon2::password_hash::{PasswordHash, PasswordVerifier};
+        return match PasswordHash::new(hash) {
+            Ok(parsed) => argon2::Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(error) => {
+                warn!("Invalid argon2 hash: {error}");
+                false
+            }
+        };
+    }
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+/// Compare two secrets without leaking the position of the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn peer_ip(peer_addr: &str) -> Option<IpAddr> {
+    peer_addr
+        .parse::<std::net::SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| peer_addr.parse::<IpAddr>())
+        .ok()
+}
+
+/// Non-empty lines that aren't comments.
+fn content_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tide::{Request, StatusCode};
+
+use crate::error::ApiError;
+use crate::templates::{self, parameter_value, TaskTemplate};
+use crate::{
+    check_add_task, error_response, json_response, mask_envs, AddTaskRequest, AppState,
+    BulkSelection, TaskActionRequest,
+};
+
+/// How the parameter lists of a batch are combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Combine {
+    /// Every combination of values, the last parameter changing fastest.
+    #[default]
+    Product,
+    /// The n-th value of every list, which all need to have the same length.
+    Zip,
+}
+
+/// The name of a stored template, or a template given inline.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum BatchTemplate {
+    Name(String),
+    Inline(TaskTemplate),
+}
+
+/// The body of `POST /tasks/batch`: either explicit `tasks`, or a `template`
+/// with lists of values for its parameters.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BatchRequest {
+    #[serde(default)]
+    pub tasks: Vec<AddTaskRequest>,
+    pub template: Option<BatchTemplate>,
+    #[serde(default)]
+    pub params: BTreeMap<String, Vec<Value>>,
+    #[serde(default)]
+    pub combine: Combine,
+    /// Overrides of the template's settings.
+    pub group: Option<String>,
+    pub priority: Option<i32>,
+    pub stashed: Option<bool>,
+    pub start_immediately: Option<bool>,
+    /// The label of all tasks, replacing their own.
+    pub label: Option<String>,
+    /// Add as many tasks as possible, instead of removing the added ones if one fails.
+    #[serde(default)]
+    pub best_effort: bool,
+    /// Only expand the tasks, without adding them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl BatchRequest {
+    /// The tasks to add, in order.
+    pub(crate) fn expand(self, state: &AppState) -> Result<Vec<AddTaskRequest>, ApiError> {
+        let Some(template) = self.template else {
+            if !self.params.is_empty() {
+                return Err(ApiError::BadRequest(
+                    "params are only used with a template".to_string(),
+                ));
+            }
+            return Ok(self
+                .tasks
+                .into_iter()
+                .map(|mut task| {
+                    task.label = self.label.clone().or(task.label);
+                    task
+                })
+                .collect());
+        };
+        if !self.tasks.is_empty() {
+            return Err(ApiError::BadRequest(
+                "Either tasks or a template, not both".to_string(),
+            ));
+        }
+        let template = match template {
+            BatchTemplate::Name(name) => templates::lookup(state, &name)?,
+            BatchTemplate::Inline(template) => {
+                template.validate()?;
+                template
+            }
+        };
+
+        let params = self
+            .params
+            .iter()
+            .map(|(name, values)| {
+                let values = values
+                    .iter()
+                    .map(|value| parameter_value(name, value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((name.clone(), values))
+            })
+            .collect::<Result<BTreeMap<_, _>, ApiError>>()?;
+        combinations(&params, self.combine, max_tasks())?
+            .iter()
+            .map(|values| {
+                let mut task = template.render(values)?;
+                task.group = self.group.clone().or(task.group);
+                task.priority = self.priority.or(task.priority);
+                task.label = self.label.clone().or(task.label);
+                task.stashed = self.stashed;
+                task.start_immediately = self.start_immediately;
+                Ok(task)
+            })
+            .collect()
+    }
+}
+
+/// The most tasks a batch may add. Defaults to 1000.
+fn max_tasks() -> usize {
+    std::env::var("PUEUE_WEBUI_BATCH_MAX_TASKS")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(1000)
+}
+
+/// The parameter values of every task of a batch. Without any parameters,
+/// that's a single task.
+pub fn combinations(
+    params: &BTreeMap<String, Vec<String>>,
+    combine: Combine,
+    limit: usize,
+) -> Result<Vec<BTreeMap<String, String>>, ApiError> {
+    let too_many = || ApiError::BadRequest(format!("A batch may add at most {limit} tasks"));
+    let count = match combine {
+        Combine::Product => params
+            .values()
+            .try_fold(1usize, |count, values| count.checked_mul(values.len()))
+            .ok_or_else(too_many)?,
+        Combine::Zip => {
+            let lengths: BTreeSet<usize> = params.values().map(Vec::len).collect();
+            if lengths.len() > 1 {
+                return Err(ApiError::BadRequest(
+                    "All parameter lists need the same length to be zipped".to_string(),
+                ));
+            }
+            lengths.into_iter().next().unwrap_or(1)
+        }
+    };
+    if count > limit {
+        return Err(too_many());
+    }
+
+    let combinations = (0..count)
+        .map(|index| {
+            let mut rest = index;
+            // Walk the parameters backwards, so that the last one changes fastest.
+            params
+                .iter()
+                .rev()
+                .map(|(name, values)| {
+                    let position = match combine {
+                        Combine::Product => {
+                            let position = rest % values.len();
+                            rest /= values.len();
+                            position
+                        }
+                        Combine::Zip => index,
+                    };
+                    (name.clone(), values[position].clone())
+                })
+                .collect()
+        })
+        .collect();
+    Ok(combinations)
+}
+
+/// The id of an added task, from the daemon's `AddedTask` response or the
+/// `New task added (id 4).` output of the CLI.
+fn added_task_id(result: &Value) -> Option<usize> {
+    if let Some(id) = result["task_id"].as_u64() {
+        return Some(id as usize);
+    }
+    let message = result["message"].as_str()?;
+    let (_, rest) = message.split_once("(id ")?;
+    rest.split(|char: char| !char.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn task_action(action: &str) -> TaskActionRequest {
+    TaskActionRequest {
+        action: action.to_string(),
+        ..Default::default()
+    }
+}
+
+/// `POST /tasks/batch`
+///
+/// Unless `best_effort` is set, all tasks are added stashed and only enqueued once
+/// every one of them has been added. If one fails, the added ones are removed again,
+/// which always works for stashed tasks. Tasks whose id the daemon didn't tell count
+/// as failed, but can't be removed and are listed as `unidentified`.
+///
+/// Once the tasks have been enqueued, nothing is removed anymore. If starting them
+/// fails then, the enqueued ones are listed as `released` instead of `rolled_back`.
+pub(crate) async fn batch_handler(mut req: Request<AppState>) -> tide::Result {
+    let body: BatchRequest = req
+        .body_json()
+        .await
+        .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body"))?;
+    let (best_effort, dry_run) = (body.best_effort, body.dry_run);
+    let state = req.state();
+    let mut tasks = body.expand(state)?;
+    if tasks.is_empty() {
+        return error_response(ApiError::BadRequest("No tasks to add".to_string()));
+    }
+    if tasks.len() > max_tasks() {
+        return error_response(ApiError::BadRequest(format!(
+            "A batch may add at most {} tasks",
+            max_tasks()
+        )));
+    }
+
+    let needs_status = tasks.iter().any(|task| {
+        task.dependencies
+            .as_ref()
+            .is_some_and(|ids| !ids.is_empty())
+    });
+    let status = if needs_status {
+        Some(state.backend.status().await.map_err(ApiError::from)?)
+    } else {
+        None
+    };
+    for (index, task) in tasks.iter_mut().enumerate() {
+        check_add_task(task, status.as_ref())
+            .map_err(|error| ApiError::BadRequest(format!("Task {index}: {}", error.message())))?;
+    }
+
+    if dry_run {
+        return json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "dry_run": true,
+                "count": tasks.len(),
+                "tasks": tasks
+                    .iter()
+                    .map(|task| {
+                        let mut envs = task.envs.clone();
+                        envs.iter_mut().for_each(mask_envs);
+                        json!({
+                            "command": task.command,
+                            "group": task.group.as_deref().unwrap_or("default"),
+                            "label": task.label,
+                            "envs": envs,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            }),
+        );
+    }
+
+    // What to do with each task once all of them have been added stashed.
+    let mut release = Vec::new();
+    if !best_effort {
+        for task in tasks.iter_mut() {
+            let stashed = task.stashed.unwrap_or(false) || task.enqueue_at.is_some();
+            let start = !stashed && task.start_immediately.unwrap_or(!stashed);
+            release.push((!stashed, start));
+            task.stashed = Some(true);
+            task.start_immediately = Some(false);
+        }
+    }
+
+    let count = tasks.len();
+    let results = state.backend.add_tasks(tasks, !best_effort).await;
+    let mut ids = Vec::new();
+    let mut unidentified = Vec::new();
+    let mut outcomes = Vec::with_capacity(count);
+    let mut failure = None;
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(result) => {
+                let id = added_task_id(&result);
+                ids.extend(id);
+                outcomes.push(json!({ "index": index, "outcome": "added", "id": id }));
+                if id.is_none() && !best_effort {
+                    unidentified.push(index);
+                    failure.get_or_insert((
+                        index,
+                        ApiError::Protocol(format!("The daemon didn't tell the id of task {index}")),
+                    ));
+                }
+            }
+            Err(error) => {
+                let error = ApiError::from(error);
+                outcomes.push(json!({
+                    "index": index,
+                    "outcome": "failed",
+                    "error": error.message(),
+                    "code": error.code(),
+                }));
+                failure.get_or_insert((index, error));
+            }
+        }
+    }
+
+    if best_effort {
+        if ids.is_empty() {
+            if let Some((_, error)) = failure {
+                let mut response = error.body();
+                response["outcomes"] = json!(outcomes);
+                return json_response(error.status(), response);
+            }
+        }
+        let failed = outcomes
+            .iter()
+            .filter(|outcome| outcome["outcome"] == "failed")
+            .count();
+        return json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "count": ids.len(),
+                "failed": failed,
+                "ids": ids,
+                "outcomes": outcomes,
+            }),
+        );
+    }
+
+    let mut released = Vec::new();
+    if failure.is_none() {
+        let to_release: Vec<(usize, (bool, bool))> = outcomes
+            .iter()
+            .zip(release)
+            .filter_map(|(outcome, release)| Some((outcome["id"].as_u64()? as usize, release)))
+            .collect();
+        let to_enqueue = to_release.iter().filter(|(_, (enqueue, _))| *enqueue);
+        let to_start = to_release.iter().filter(|(_, (_, start))| *start);
+        let actions = [
+            ("enqueue", to_enqueue.map(|(id, _)| *id).collect::<Vec<_>>()),
+            ("start", to_start.map(|(id, _)| *id).collect()),
+        ];
+        for (action, ids) in actions {
+            if ids.is_empty() {
+                continue;
+            }
+            let result = state
+                .backend
+                .bulk_action(BulkSelection::Ids(ids.clone()), &task_action(action))
+                .await;
+            match result {
+                Ok(_) if action == "enqueue" => released = ids,
+                Ok(_) => {}
+                Err(error) => {
+                    failure = Some((count, ApiError::from(error)));
+                    break;
+                }
+            }
+        }
+    }
+
+    let Some((index, error)) = failure else {
+        return json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "count": ids.len(),
+                "ids": ids,
+                "outcomes": outcomes,
+            }),
+        );
+    };
+
+    let mut response = error.body();
+    response["index"] = json!(index);
+    response["outcomes"] = json!(outcomes);
+    if !unidentified.is_empty() {
+        response["unidentified"] = json!(unidentified);
+    }
+    if !released.is_empty() {
+        // Enqueued tasks may already be running, so they're left alone.
+        response["ids"] = json!(ids);
+        response["released"] = json!(released);
+    } else if !ids.is_empty() {
+        // All or nothing: remove everything that has been added.
+        let removed = state
+            .backend
+            .bulk_action(BulkSelection::Ids(ids.clone()), &task_action("remove"))
+            .await;
+        match removed {
+            Ok(_) => response["rolled_back"] = json!(ids),
+            Err(rollback) => response["rollback_error"] = json!(rollback.to_string()),
+        }
+    }
+    json_response(error.status(), response)
+}
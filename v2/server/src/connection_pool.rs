@@ -0,0 +1,383 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use async_std::channel::{Receiver, Sender};
+use log::{debug, warn};
+
+use pueue_lib::message::{GroupRequest, Request, Response};
+use pueue_lib::network_blocking::protocol::{receive_bytes, send_bytes};
+use pueue_lib::network_blocking::socket::{
+    get_tls_connector, BlockingStream, ConnectionSettings, GenericBlockingStream,
+};
+use pueue_lib::network_blocking::BlockingClient;
+use pueue_lib::secret::read_shared_secret;
+use pueue_lib::settings::Settings;
+
+use crate::error::ApiError;
+
+/// Returns `true` if the worker has been replaced while running the job.
+type Job = Box<dyn FnOnce(&mut PooledConnection) -> bool + Send>;
+
+/// A fixed set of worker threads, each owning one authenticated daemon connection.
+///
+/// [`BlockingClient`] isn't `Send`, so connections can't be handed out directly.
+/// Instead, requests are queued as jobs and executed by whichever worker is free.
+///
+/// Workers that panic or hang beyond the request timeout are replaced, so the pool
+/// keeps its size. A hung worker exits once its request returns after all.
+pub struct ConnectionPool {
+    jobs: Sender<Job>,
+    workers: Arc<Workers>,
+    timeout: Duration,
+}
+
+/// What every worker needs to run, and to replace itself.
+struct Workers {
+    jobs: Receiver<Job>,
+    settings: Settings,
+    max_idle: Duration,
+    spawned: AtomicUsize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Queued,
+    Running,
+    /// The request timed out while running and the worker has been replaced.
+    Abandoned,
+}
+
+impl ConnectionPool {
+    pub fn new(settings: Settings, size: usize, max_idle: Duration, timeout: Duration) -> Self {
+        let (jobs, receiver) = async_std::channel::unbounded::<Job>();
+        let workers = Arc::new(Workers {
+            jobs: receiver,
+            settings,
+            max_idle,
+            spawned: AtomicUsize::new(0),
+        });
+        for _ in 0..size.max(1) {
+            Workers::spawn(&workers);
+        }
+        Self {
+            jobs,
+            workers,
+            timeout,
+        }
+    }
+
+    pub fn from_env(settings: Settings) -> Self {
+        let size = std::env::var("PUEUE_WEBUI_POOL_SIZE")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(4);
+        let max_idle = std::env::var("PUEUE_WEBUI_POOL_MAX_IDLE_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(30);
+        let timeout = std::env::var("PUEUE_WEBUI_POOL_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(30);
+        Self::new(
+            settings,
+            size,
+            Duration::from_secs(max_idle),
+            Duration::from_secs(timeout),
+        )
+    }
+
+    /// Run `handler` on a pooled connection.
+    ///
+    /// Fails if the daemon doesn't answer within the pool's timeout, including the
+    /// time spent waiting for a free connection.
+    pub async fn run<F, R>(&self, handler: F) -> Result<R>
+    where
+        F: FnOnce(&mut BlockingClient) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = async_std::channel::bounded(1);
+        let state = Arc::new(Mutex::new(JobState::Queued));
+        let job: Job = Box::new({
+            let state = state.clone();
+            move |connection| {
+                {
+                    let mut state = state.lock().unwrap_or_else(|err| err.into_inner());
+                    // Nobody waits for the result anymore, as it timed out in the queue.
+                    if sender.is_closed() {
+                        return false;
+                    }
+                    *state = JobState::Running;
+                }
+                let _ = sender.send_blocking(connection.run(handler));
+                let state = *state.lock().unwrap_or_else(|err| err.into_inner());
+                state == JobState::Abandoned
+            }
+        });
+        self.jobs
+            .send(job)
+            .await
+            .map_err(|_| anyhow!("Connection pool has been shut down"))?;
+        match async_std::future::timeout(self.timeout, receiver.recv()).await {
+            Ok(result) => result.map_err(|_| anyhow!("Connection worker went away"))?,
+            Err(_) => {
+                {
+                    let mut state = state.lock().unwrap_or_else(|err| err.into_inner());
+                    drop(receiver);
+                    if *state == JobState::Running {
+                        *state = JobState::Abandoned;
+                        warn!("Daemon connection hung for {:?}, replacing it", self.timeout);
+                        Workers::spawn(&self.workers);
+                    }
+                }
+                Err(anyhow!(ApiError::DaemonUnreachable(format!(
+                    "The daemon didn't answer within {}s",
+                    self.timeout.as_secs_f64()
+                ))))
+            }
+        }
+    }
+}
+
+impl Workers {
+    fn spawn(workers: &Arc<Workers>) {
+        let index = workers.spawned.fetch_add(1, Ordering::SeqCst);
+        let worker = Worker {
+            workers: workers.clone(),
+        };
+        let spawned = std::thread::Builder::new()
+            .name(format!("pueue-connection-{index}"))
+            .spawn(move || worker.run());
+        if let Err(error) = spawned {
+            warn!("Failed to spawn connection worker {index}: {error}");
+        }
+    }
+}
+
+/// A worker thread. It's replaced by a new one if it panics.
+struct Worker {
+    workers: Arc<Workers>,
+}
+
+impl Worker {
+    fn run(&self) {
+        let workers = &self.workers;
+        let mut connection = PooledConnection::new(workers.settings.clone(), workers.max_idle);
+        while let Ok(job) = workers.jobs.recv_blocking() {
+            if job(&mut connection) {
+                debug!("Stopping connection worker that has been replaced");
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            warn!("Connection worker panicked, starting a new one");
+            Workers::spawn(&self.workers);
+        }
+    }
+}
+
+struct PooledConnection {
+    settings: Settings,
+    max_idle: Duration,
+    secret: Option<Vec<u8>>,
+    client: Option<BlockingClient>,
+    last_used: Instant,
+}
+
+impl PooledConnection {
+    fn new(settings: Settings, max_idle: Duration) -> Self {
+        Self {
+            settings,
+            max_idle,
+            secret: None,
+            client: None,
+            last_used: Instant::now(),
+        }
+    }
+
+    fn run<F, R>(&mut self, handler: F) -> Result<R>
+    where
+        F: FnOnce(&mut BlockingClient) -> Result<R>,
+    {
+        let result = handler(self.client()?);
+        self.last_used = Instant::now();
+        // Protocol and IO errors leave the connection in an unknown state.
+        // Daemon failures are plain responses and don't affect the connection.
+        if let Err(error) = &result {
+            if error.downcast_ref::<pueue_lib::Error>().is_some() {
+                debug!("Dropping daemon connection after error: {error}");
+                self.client = None;
+            }
+        }
+        result
+    }
+
+    /// Get a healthy client, reconnecting if necessary.
+    fn client(&mut self) -> Result<&mut BlockingClient> {
+        let stale = self.last_used.elapsed() > self.max_idle;
+        if let Some(client) = self.client.as_mut() {
+            if stale && !is_healthy(client) {
+                debug!("Idle daemon connection is gone, reconnecting");
+                self.client = None;
+            }
+        }
+
+        match self.client {
+            Some(ref mut client) => Ok(client),
+            None => {
+                let client = self.connect()?;
+                Ok(self.client.insert(client))
+            }
+        }
+    }
+
+    fn connect(&mut self) -> Result<BlockingClient> {
+        // The secret might have changed if the daemon has been set up again,
+        // so re-read it once before giving up.
+        match self.secret.as_ref() {
+            Some(secret) => match connect_with(&self.settings, secret) {
+                Ok(client) => Ok(client),
+                Err(_) => {
+                    self.secret = None;
+                    self.connect()
+                }
+            },
+            None => {
+                let secret_path = self.settings.shared.shared_secret_path();
+                let secret = read_shared_secret(secret_path.as_path())?;
+                let client = connect_with(&self.settings, &secret)?;
+                self.secret = Some(secret);
+                Ok(client)
+            }
+        }
+    }
+}
+
+/// How often a quiet log stream checks whether anybody still listens.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Open a dedicated, unpooled connection for a long running log stream.
+///
+/// Reads on it wake up every [`STREAM_POLL_INTERVAL`] and fail once `is_closed` returns
+/// `true`, so the stream's thread doesn't wait for a quiet daemon after its client left.
+pub(crate) fn connect_stream(
+    settings: &Settings,
+    is_closed: impl Fn() -> bool + 'static,
+) -> Result<BlockingClient> {
+    let secret_path = settings.shared.shared_secret_path();
+    let secret = read_shared_secret(secret_path.as_path())?;
+
+    let unreachable = |error: std::io::Error| anyhow!(ApiError::DaemonUnreachable(error.to_string()));
+    // pueue-lib boxes its sockets right away, so they are opened here to set the timeout.
+    let stream: GenericBlockingStream = match ConnectionSettings::try_from(settings.shared.clone())? {
+        #[cfg(not(target_os = "windows"))]
+        ConnectionSettings::UnixSocket { path } => {
+            let socket = std::os::unix::net::UnixStream::connect(path).map_err(unreachable)?;
+            socket
+                .set_read_timeout(Some(STREAM_POLL_INTERVAL))
+                .map_err(unreachable)?;
+            Box::new(socket)
+        }
+        ConnectionSettings::TlsTcpSocket {
+            host,
+            port,
+            certificate,
+        } => {
+            let socket = TcpStream::connect(format!("{host}:{port}")).map_err(unreachable)?;
+            let timeout_handle = socket.try_clone().map_err(unreachable)?;
+            let stream = get_tls_connector(certificate)?
+                .connect("pueue.local", socket)
+                .map_err(|error| ApiError::DaemonUnreachable(format!("TLS handshake failed: {error}")))?;
+            // Only now, as the TLS handshake doesn't expect reads to time out.
+            timeout_handle
+                .set_read_timeout(Some(STREAM_POLL_INTERVAL))
+                .map_err(unreachable)?;
+            Box::new(stream)
+        }
+    };
+    let mut stream: GenericBlockingStream = Box::new(WatchedStream { stream, is_closed });
+
+    // The same handshake as in `BlockingClient::new`.
+    send_bytes(&secret, &mut stream)?;
+    let daemon_version = String::from_utf8(receive_bytes(&mut stream)?).unwrap_or_default();
+    if daemon_version.is_empty() {
+        bail!(ApiError::SecretRejected(
+            "Daemon went away after sending secret. Did you use the correct secret?".to_string()
+        ));
+    }
+    Ok(BlockingClient {
+        stream,
+        daemon_version,
+    })
+}
+
+/// A daemon connection whose timed out reads are retried for as long as `is_closed`
+/// returns `false`. Retrying keeps half-read messages intact.
+struct WatchedStream<F> {
+    stream: GenericBlockingStream,
+    is_closed: F,
+}
+
+impl<F: Fn() -> bool> Read for WatchedStream<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(error)
+                    if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    if (self.is_closed)() {
+                        return Err(std::io::Error::new(
+                            ErrorKind::ConnectionAborted,
+                            "Nobody listens to the stream anymore",
+                        ));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<F> Write for WatchedStream<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<F: Fn() -> bool> BlockingStream for WatchedStream<F> {}
+
+fn connect_with(settings: &Settings, secret: &[u8]) -> Result<BlockingClient> {
+    let connection_settings = ConnectionSettings::try_from(settings.shared.clone())?;
+    BlockingClient::new(connection_settings, secret, true).map_err(|report| {
+        // Keep pueue's error type, so the web server can tell why the daemon is unreachable.
+        let error = match report.downcast_ref::<pueue_lib::Error>() {
+            Some(error) => ApiError::from(error),
+            None if report.to_string().contains("correct secret") => {
+                ApiError::SecretRejected(report.to_string())
+            }
+            None => ApiError::DaemonUnreachable(format!("{report:#}")),
+        };
+        anyhow!(error)
+    })
+}
+
+/// Cheap round trip to check whether the daemon still listens on this connection.
+fn is_healthy(client: &mut BlockingClient) -> bool {
+    client
+        .send_request(Request::Group(GroupRequest::List))
+        .is_ok()
+        && matches!(client.receive_response(), Ok(Response::Group(_)))
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use pueue_lib::message::EditableTask;
+
+use crate::PueueBackend;
+
+/// Tasks that have been locked for editing via `GET /task/:id/edit`.
+///
+/// Every lock is guarded by a watchdog, which restores the task if it's neither
+/// submitted nor released in time. That way a closed browser tab can't leave a task
+/// locked forever.
+pub struct EditSessions {
+    timeout: Duration,
+    next_token: AtomicU64,
+    sessions: Mutex<HashMap<usize, (u64, EditableTask)>>,
+}
+
+impl EditSessions {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_token: AtomicU64::new(0),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let timeout = std::env::var("PUEUE_WEBUI_EDIT_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(300);
+        Self::new(Duration::from_secs(timeout))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// The locked task, if there's an open edit session for it.
+    pub fn get(&self, task_id: usize) -> Option<EditableTask> {
+        self.lock().get(&task_id).map(|(_, task)| task.clone())
+    }
+
+    /// Track a freshly locked task. Restarts the watchdog if it's already tracked.
+    pub fn start(
+        sessions: &Arc<EditSessions>,
+        backend: Arc<dyn PueueBackend>,
+        task: EditableTask,
+    ) {
+        let task_id = task.id;
+        let token = sessions.next_token.fetch_add(1, Ordering::SeqCst);
+        sessions.lock().insert(task_id, (token, task));
+
+        let sessions = sessions.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(sessions.timeout).await;
+            let expired = {
+                let mut guard = sessions.lock();
+                match guard.get(&task_id) {
+                    Some((current, _)) if *current == token => guard.remove(&task_id).is_some(),
+                    _ => false,
+                }
+            };
+            if expired {
+                info!("Edit of task {task_id} timed out, restoring it");
+                if let Err(error) = backend.edit_restore(task_id).await {
+                    warn!("Failed to restore task {task_id} after edit timeout: {error}");
+                }
+            }
+        });
+    }
+
+    /// Close the edit session of a task, returning the locked task.
+    pub fn finish(&self, task_id: usize) -> Option<EditableTask> {
+        self.lock().remove(&task_id).map(|(_, task)| task)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<usize, (u64, EditableTask)>> {
+        self.sessions.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use serde_json::json;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+/// Everything that can go wrong in a request, with a stable `code` for clients.
+///
+/// Error responses look like `{"ok": false, "error": "<message>", "code": "<code>"}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    /// The request doesn't fit the current state, e.g. editing a running task.
+    Conflict(String),
+    /// The daemon refused the request with a `Failure` that couldn't be classified further.
+    DaemonFailure(String),
+    /// The daemon isn't running or doesn't accept connections.
+    DaemonUnreachable(String),
+    /// The daemon closed the connection after the handshake, usually because of a wrong secret.
+    SecretRejected(String),
+    /// The socket, secret or other files of the daemon couldn't be accessed.
+    DaemonFiles(String),
+    /// The daemon's TLS certificate is missing or invalid.
+    Certificate(String),
+    /// The daemon sent something unexpected, usually because of a version mismatch.
+    Protocol(String),
+    /// A message exceeded the size limit of the protocol.
+    MessageTooBig(String),
+    /// The pueue config couldn't be read.
+    Config(String),
+    Internal(String),
+}
+
+impl ApiError {
+    /// Classify a `Failure` text of the daemon.
+    pub fn daemon_failure(text: impl Into<String>) -> Self {
+        let text = text.into();
+        let lower = text.to_lowercase();
+        if ["doesn't exist", "does not exist", "don't exist", "not found", "no task"]
+            .iter()
+            .any(|marker| lower.contains(marker))
+        {
+            ApiError::NotFound(text)
+        } else if ["already", "locked", "can't", "cannot"]
+            .iter()
+            .any(|marker| lower.contains(marker))
+        {
+            ApiError::Conflict(text)
+        } else {
+            ApiError::DaemonFailure(text)
+        }
+    }
+
+    /// Classify the error output of the `pueue` CLI, which is either a daemon
+    /// failure or the CLI failing to connect at all.
+    pub fn cli_failure(stderr: impl Into<String>) -> Self {
+        let stderr = stderr.into();
+        let lower = stderr.to_lowercase();
+        if ["connect", "socket", "secret"].iter().any(|marker| lower.contains(marker)) {
+            ApiError::DaemonUnreachable(stderr)
+        } else {
+            ApiError::daemon_failure(stderr)
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::DaemonFailure(_) => "daemon_failure",
+            ApiError::DaemonUnreachable(_) => "daemon_unreachable",
+            ApiError::SecretRejected(_) => "secret_rejected",
+            ApiError::DaemonFiles(_) => "daemon_files_unavailable",
+            ApiError::Certificate(_) => "certificate_invalid",
+            ApiError::Protocol(_) => "protocol_mismatch",
+            ApiError::MessageTooBig(_) => "message_too_big",
+            ApiError::Config(_) => "config_invalid",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BadRequest,
+            ApiError::Unauthorized(_) => StatusCode::Unauthorized,
+            ApiError::Forbidden(_) => StatusCode::Forbidden,
+            ApiError::NotFound(_) => StatusCode::NotFound,
+            ApiError::Conflict(_) => StatusCode::Conflict,
+            ApiError::DaemonFailure(_) => StatusCode::UnprocessableEntity,
+            ApiError::DaemonUnreachable(_)
+            | ApiError::DaemonFiles(_)
+            | ApiError::Certificate(_) => StatusCode::ServiceUnavailable,
+            ApiError::SecretRejected(_) | ApiError::Protocol(_) => StatusCode::BadGateway,
+            ApiError::MessageTooBig(_) => StatusCode::PayloadTooLarge,
+            ApiError::Config(_) | ApiError::Internal(_) => StatusCode::InternalServerError,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::DaemonFailure(message)
+            | ApiError::DaemonUnreachable(message)
+            | ApiError::SecretRejected(message)
+            | ApiError::DaemonFiles(message)
+            | ApiError::Certificate(message)
+            | ApiError::Protocol(message)
+            | ApiError::MessageTooBig(message)
+            | ApiError::Config(message)
+            | ApiError::Internal(message) => message,
+        }
+    }
+
+    /// The same kind of error with another message.
+    pub fn with_message(&self, message: impl Into<String>) -> Self {
+        let message = message.into();
+        match self {
+            ApiError::BadRequest(_) => ApiError::BadRequest(message),
+            ApiError::Unauthorized(_) => ApiError::Unauthorized(message),
+            ApiError::Forbidden(_) => ApiError::Forbidden(message),
+            ApiError::NotFound(_) => ApiError::NotFound(message),
+            ApiError::Conflict(_) => ApiError::Conflict(message),
+            ApiError::DaemonFailure(_) => ApiError::DaemonFailure(message),
+            ApiError::DaemonUnreachable(_) => ApiError::DaemonUnreachable(message),
+            ApiError::SecretRejected(_) => ApiError::SecretRejected(message),
+            ApiError::DaemonFiles(_) => ApiError::DaemonFiles(message),
+            ApiError::Certificate(_) => ApiError::Certificate(message),
+            ApiError::Protocol(_) => ApiError::Protocol(message),
+            ApiError::MessageTooBig(_) => ApiError::MessageTooBig(message),
+            ApiError::Config(_) => ApiError::Config(message),
+            ApiError::Internal(_) => ApiError::Internal(message),
+        }
+    }
+
+    pub fn body(&self) -> serde_json::Value {
+        json!({
+            "ok": false,
+            "error": self.message(),
+            "code": self.code(),
+        })
+    }
+
+    pub fn response(&self) -> Response {
+        let mut response = Response::new(self.status());
+        response.set_body(self.body());
+        response
+    }
+
+    /// The error for a status code, used for errors that only carry a status.
+    fn from_status(status: StatusCode, message: String) -> Self {
+        match status {
+            StatusCode::BadRequest | StatusCode::UnprocessableEntity => ApiError::BadRequest(message),
+            StatusCode::Unauthorized => ApiError::Unauthorized(message),
+            StatusCode::Forbidden => ApiError::Forbidden(message),
+            StatusCode::NotFound => ApiError::NotFound(message),
+            StatusCode::Conflict => ApiError::Conflict(message),
+            _ => ApiError::Internal(message),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<&pueue_lib::Error> for ApiError {
+    fn from(error: &pueue_lib::Error) -> Self {
+        use pueue_lib::Error;
+
+        let message = error.to_string();
+        match error {
+            Error::Connection(_) | Error::IoError(..) | Error::RawIoError(_) => {
+                ApiError::DaemonUnreachable(message)
+            }
+            // The socket is missing if the daemon isn't running.
+            Error::IoPathError(_, action, _) if action.starts_with("connecting") => {
+                ApiError::DaemonUnreachable(message)
+            }
+            Error::IoPathError(..) | Error::InvalidPath(_) => ApiError::DaemonFiles(message),
+            Error::CertificateFailure(_) => ApiError::Certificate(message),
+            Error::EmptyPayload
+            | Error::MessageDeserialization(_)
+            | Error::MessageSerialization(_)
+            | Error::UnexpectedPayload(_) => ApiError::Protocol(message),
+            Error::MessageTooBig(..) => ApiError::MessageTooBig(message),
+            Error::ConfigDeserialization(_) => ApiError::Config(message),
+            Error::Generic(_) | Error::UnixSocketExists => ApiError::Internal(message),
+        }
+    }
+}
+
+impl From<pueue_lib::Error> for ApiError {
+    fn from(error: pueue_lib::Error) -> Self {
+        (&error).into()
+    }
+}
+
+impl From<&anyhow::Error> for ApiError {
+    fn from(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<ApiError>() {
+                return error.clone();
+            }
+            if let Some(error) = cause.downcast_ref::<pueue_lib::Error>() {
+                return error.into();
+            }
+        }
+        ApiError::Internal(error.to_string())
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        (&error).into()
+    }
+}
+
+/// Turns errors returned by handlers, e.g. for invalid JSON bodies, into error bodies.
+///
+/// Handlers may also return an [`ApiError`] via `?`, whose status and code are used then.
+pub(crate) struct ErrorBody;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ErrorBody {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = next.run(req).await;
+        if let Some((status, error)) = response_error(&res) {
+            res.set_status(status);
+            res.set_body(error.body());
+        }
+        Ok(res)
+    }
+}
+
+/// The status and error a response with an error is going to be sent with by [`ErrorBody`].
+///
+/// Until then, the response's own status is 500 for errors returned via `?`.
+pub(crate) fn response_error(res: &Response) -> Option<(StatusCode, ApiError)> {
+    let error = res.error()?;
+    Some(match error.downcast_ref::<ApiError>() {
+        Some(api_error) => (api_error.status(), api_error.clone()),
+        None => (
+            error.status(),
+            ApiError::from_status(error.status(), error.to_string()),
+        ),
+    })
+}
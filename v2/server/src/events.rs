@@ -0,0 +1,209 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::channel::{Receiver, Sender, TrySendError};
+use log::debug;
+use pueue_lib::state::{Group, State};
+use pueue_lib::task::{Task, TaskStatus};
+use serde::Serialize;
+
+use crate::{compute_group_stats, mask_envs, mask_state_envs, AppState, StatusCacheEntry};
+
+/// A change of the daemon's state, as broadcast on `/events`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StatusEvent {
+    /// The full status. Sent to every subscriber first, diffs are relative to it.
+    Snapshot { status: State },
+    TaskAdded { id: usize, task: Task },
+    TaskStatusChanged {
+        id: usize,
+        from: String,
+        to: String,
+        status: TaskStatus,
+    },
+    TaskRemoved { id: usize },
+    /// A group was added or changed. `group` is `None` if it has been removed.
+    GroupChanged { name: String, group: Option<Group> },
+    /// Polling the daemon failed. Sent once per distinct error.
+    Error { error: String },
+}
+
+impl StatusEvent {
+    /// The SSE event name of this event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatusEvent::Snapshot { .. } => "snapshot",
+            StatusEvent::TaskAdded { .. } => "task_added",
+            StatusEvent::TaskStatusChanged { .. } => "task_status_changed",
+            StatusEvent::TaskRemoved { .. } => "task_removed",
+            StatusEvent::GroupChanged { .. } => "group_changed",
+            StatusEvent::Error { .. } => "error",
+        }
+    }
+}
+
+/// Shares a single status poll loop between all `/events` subscribers.
+///
+/// The loop is started by the first subscriber and stops once the last one went away.
+pub struct EventHub {
+    interval: Duration,
+    inner: Mutex<HubInner>,
+}
+
+#[derive(Default)]
+struct HubInner {
+    polling: bool,
+    latest: Option<State>,
+    last_error: Option<String>,
+    subscribers: Vec<Sender<StatusEvent>>,
+}
+
+impl EventHub {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            inner: Mutex::new(HubInner::default()),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let interval = std::env::var("PUEUE_WEBUI_EVENTS_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(1000);
+        Self::new(Duration::from_millis(interval))
+    }
+
+    /// Register a new subscriber and make sure the poll loop is running.
+    pub fn subscribe(hub: &Arc<EventHub>, state: &AppState) -> Receiver<StatusEvent> {
+        let (sender, receiver) = async_std::channel::bounded(256);
+        let start = {
+            let mut inner = hub.inner.lock().unwrap_or_else(|err| err.into_inner());
+            if let Some(status) = inner.latest.clone() {
+                let _ = sender.try_send(StatusEvent::Snapshot { status });
+            }
+            inner.subscribers.push(sender);
+            !std::mem::replace(&mut inner.polling, true)
+        };
+
+        if start {
+            let hub = hub.clone();
+            let state = state.clone();
+            async_std::task::spawn(async move { hub.poll(state).await });
+        }
+        receiver
+    }
+
+    async fn poll(&self, state: AppState) {
+        loop {
+            let result = state.backend.status().await;
+            {
+                let mut inner = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+                let events = match result {
+                    Ok(status) => {
+                        let events = match inner.latest.as_ref() {
+                            Some(previous) => diff_status(previous, &status),
+                            None => vec![StatusEvent::Snapshot {
+                                status: status.clone(),
+                            }],
+                        };
+                        store_in_cache(&state, &status);
+                        inner.latest = Some(status);
+                        inner.last_error = None;
+                        events
+                    }
+                    Err(error) => {
+                        let error = error.to_string();
+                        if inner.last_error.as_ref() == Some(&error) {
+                            Vec::new()
+                        } else {
+                            inner.last_error = Some(error.clone());
+                            vec![StatusEvent::Error { error }]
+                        }
+                    }
+                };
+
+                // Drop subscribers that went away or can't keep up.
+                inner.subscribers.retain(|subscriber| {
+                    events.iter().all(|event| match subscriber.try_send(event.clone()) {
+                        Ok(()) => true,
+                        Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => false,
+                    }) && !subscriber.is_closed()
+                });
+
+                if inner.subscribers.is_empty() {
+                    debug!("No event subscribers left, stopping status poll loop");
+                    inner.polling = false;
+                    inner.latest = None;
+                    inner.last_error = None;
+                    return;
+                }
+            }
+            async_std::task::sleep(self.interval).await;
+        }
+    }
+}
+
+fn store_in_cache(state: &AppState, status: &State) {
+    let (stats, digest) = compute_group_stats(status);
+    if let Ok(mut cache) = state.status_cache.lock() {
+        cache.value = Some(StatusCacheEntry {
+            at: Instant::now(),
+            payload: status.clone(),
+            stats,
+            digest,
+        });
+    }
+}
+
+/// Compute the typed changes between two daemon states.
+pub fn diff_status(previous: &State, current: &State) -> Vec<StatusEvent> {
+    let mut events = Vec::new();
+    let mut ids: BTreeSet<usize> = previous.tasks.keys().copied().collect();
+    ids.extend(current.tasks.keys());
+    for id in ids {
+        match (previous.tasks.get(&id), current.tasks.get(&id)) {
+            (None, Some(task)) => events.push(StatusEvent::TaskAdded {
+                id,
+                task: task.clone(),
+            }),
+            (Some(_), None) => events.push(StatusEvent::TaskRemoved { id }),
+            (Some(old), Some(new)) if old.status != new.status => {
+                events.push(StatusEvent::TaskStatusChanged {
+                    id,
+                    from: old.status.to_string(),
+                    to: new.status.to_string(),
+                    status: new.status.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let mut names: BTreeSet<&String> = previous.groups.keys().collect();
+    names.extend(current.groups.keys());
+    for name in names {
+        let new = current.groups.get(name);
+        if previous.groups.get(name) != new {
+            events.push(StatusEvent::GroupChanged {
+                name: name.clone(),
+                group: new.cloned(),
+            });
+        }
+    }
+
+    events
+}
+
+/// The SSE data of an event, with secret environment variables masked.
+pub(crate) fn event_data(event: &StatusEvent) -> String {
+    let mut event = event.clone();
+    match &mut event {
+        StatusEvent::Snapshot { status } => mask_state_envs(status),
+        StatusEvent::TaskAdded { task, .. } => mask_envs(&mut task.envs),
+        _ => {}
+    }
+    serde_json::to_string(&event).unwrap_or_else(|_| "{}".to_string())
+}
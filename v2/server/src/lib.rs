@@ -1,47 +1,285 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Local};
 use serde::Deserialize;
 use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tide::http::mime;
 use tide::{Request, Response, StatusCode};
 
+pub mod analytics;
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod batch;
+pub mod connection_pool;
+pub mod edits;
+pub mod error;
+pub mod events;
+pub mod metrics;
 pub mod pueue_backend;
+pub mod roles;
+pub mod templates;
+pub mod tls;
+use archive::Archive;
+use audit::{AuditLog, AuditMiddleware};
+use auth::AuthMiddleware;
+use edits::EditSessions;
+use error::{ApiError, ErrorBody};
+use events::EventHub;
+use metrics::{MatchedRoute, Metrics, MetricsMiddleware};
+use pueue_lib::message::{EditableTask, Signal, TaskSelection, TaskToRestart};
 use pueue_lib::settings::Settings;
+use pueue_lib::state::State;
+use pueue_lib::task::{Task, TaskResult, TaskStatus};
+use roles::RolePolicy;
+use templates::TemplateStore;
 
 #[async_trait]
 pub trait PueueBackend: Send + Sync {
-    async fn status(&self) -> Result<serde_json::Value>;
+    async fn status(&self) -> Result<State>;
     async fn logs(&self, task_id: usize, lines: Option<usize>) -> Result<serde_json::Value>;
-    async fn action(&self, task_id: usize, action: &str) -> Result<serde_json::Value>;
+    async fn action(&self, task_id: usize, request: &TaskActionRequest)
+        -> Result<serde_json::Value>;
+    async fn bulk_action(
+        &self,
+        selection: BulkSelection,
+        request: &TaskActionRequest,
+    ) -> Result<serde_json::Value>;
     async fn add_task(&self, request: AddTaskRequest) -> Result<serde_json::Value>;
+    /// Add several tasks in order, over a single daemon connection if possible. With
+    /// `stop_on_failure`, nothing is added after the first failure, which is the last result then.
+    async fn add_tasks(
+        &self,
+        requests: Vec<AddTaskRequest>,
+        stop_on_failure: bool,
+    ) -> Vec<Result<serde_json::Value>> {
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            let result = self.add_task(request).await;
+            let failed = result.is_err();
+            results.push(result);
+            if failed && stop_on_failure {
+                break;
+            }
+        }
+        results
+    }
     async fn group_action(&self, request: GroupActionRequest) -> Result<serde_json::Value>;
+    /// Follow the output of a task. The returned channel is closed once the task finished.
+    async fn stream_logs(&self, task_id: usize, lines: Option<usize>) -> Result<LogStream>;
+    /// Lock a task for editing and return its editable details.
+    async fn edit_request(&self, task_id: usize) -> Result<EditableTask>;
+    /// Save the edited details of a locked task, which also unlocks it.
+    async fn edit_submit(&self, task: EditableTask) -> Result<serde_json::Value>;
+    /// Unlock a task without applying any changes.
+    async fn edit_restore(&self, task_id: usize) -> Result<serde_json::Value>;
+    /// Write `input` to the stdin of a running task.
+    async fn send_input(&self, task_id: usize, input: String) -> Result<serde_json::Value>;
+    async fn set_env(&self, task_id: usize, key: String, value: String)
+        -> Result<serde_json::Value>;
+    async fn unset_env(&self, task_id: usize, key: String) -> Result<serde_json::Value>;
+    /// Remove finished tasks, optionally only successful ones or those of a single group.
+    async fn clean(&self, successful_only: bool, group: Option<String>)
+        -> Result<serde_json::Value>;
+    /// Swap the queue positions of two queued or stashed tasks.
+    async fn switch_tasks(&self, first: usize, second: usize) -> Result<serde_json::Value>;
+}
+
+/// Receiving end of a live log stream, as returned by [`PueueBackend::stream_logs`].
+pub type LogStream = async_std::channel::Receiver<LogStreamEvent>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogStreamEvent {
+    /// The next chunk of output of the followed task.
+    Chunk(String),
+    /// The stream failed. No further events are sent afterwards.
+    Error(String),
 }
 
 #[derive(Clone)]
 pub struct AppState {
+    name: String,
     backend: Arc<dyn PueueBackend>,
     status_cache: Arc<Mutex<StatusCache>>,
+    events: Arc<EventHub>,
+    edits: Arc<EditSessions>,
+    config_path: Option<PathBuf>,
+    profile: Option<String>,
+    /// All daemons served by this app. Only populated on the top-level app.
+    daemons: Arc<Vec<(String, AppState)>>,
+    archive: Option<Arc<Archive>>,
+    templates: Option<Arc<TemplateStore>>,
+}
+
+impl AppState {
+    fn new(daemon: Daemon) -> Self {
+        Self {
+            name: daemon.name,
+            backend: daemon.backend,
+            status_cache: Arc::new(Mutex::new(StatusCache::default())),
+            events: Arc::new(EventHub::from_env()),
+            edits: Arc::new(EditSessions::from_env()),
+            config_path: daemon.config_path,
+            profile: daemon.profile,
+            daemons: Arc::new(Vec::new()),
+            archive: None,
+            templates: None,
+        }
+    }
+}
+
+/// A named pueue daemon served by the web server.
+pub struct Daemon {
+    pub name: String,
+    pub backend: Arc<dyn PueueBackend>,
+    /// The `pueue.yml` edited by `/config/callback`.
+    pub config_path: Option<PathBuf>,
+    /// The profile of `config_path` the daemon runs with. `/config/callback` then
+    /// edits the profile's settings instead of the top-level ones.
+    pub profile: Option<String>,
+}
+
+impl Daemon {
+    pub fn new(name: impl Into<String>, backend: Arc<dyn PueueBackend>) -> Self {
+        Self {
+            name: name.into(),
+            backend,
+            config_path: config_path_override(),
+            profile: None,
+        }
+    }
+}
+
+/// Access control of the app. Everything is allowed by default.
+#[derive(Default)]
+pub struct AppOptions {
+    pub auth: Option<AuthMiddleware>,
+    /// Only takes effect together with `auth`, as roles are assigned to authenticated users.
+    pub roles: Option<RolePolicy>,
+    /// Record all mutating requests and serve them on `/audit`.
+    pub audit: Option<Arc<AuditLog>>,
+    /// Keep finished tasks of all daemons and serve them on `/history`.
+    pub archive: Option<Arc<Archive>>,
+    /// Named task templates, shared by all daemons.
+    pub templates: Option<Arc<TemplateStore>>,
 }
 
 pub fn create_app(backend: Arc<dyn PueueBackend>) -> tide::Server<AppState> {
-    let mut app = tide::with_state(AppState {
-        backend,
-        status_cache: Arc::new(Mutex::new(StatusCache::default())),
-    });
-    app.at("/health").get(health_handler);
-    app.at("/status").get(status_handler);
-    app.at("/logs/:id").get(logs_handler);
-    app.at("/tasks").post(add_task_handler);
-    app.at("/groups").post(group_handler);
-    app.at("/config/callback")
+    create_multi_app(Daemon::new("default", backend), Vec::new(), AppOptions::default())
+}
+
+/// Serve several daemons. Each one is available under `/d/:name/...`, while the
+/// primary one is also served on the top-level routes.
+pub fn create_multi_app(
+    primary: Daemon,
+    others: Vec<Daemon>,
+    options: AppOptions,
+) -> tide::Server<AppState> {
+    let states: Vec<(String, AppState)> = std::iter::once(primary)
+        .chain(others)
+        .map(|daemon| {
+            let mut state = AppState::new(daemon);
+            state.archive = options.archive.clone();
+            state.templates = options.templates.clone();
+            (state.name.clone(), state)
+        })
+        .collect();
+
+    let mut root = states[0].1.clone();
+    root.daemons = Arc::new(states.clone());
+
+    let mut app = tide::with_state(root);
+    let metrics = Arc::new(Metrics::default());
+    app.with(MetricsMiddleware::new(metrics.clone()));
+    app.with(ErrorBody);
+    // Nested daemon apps are endpoints of this app, so this covers their routes as well.
+    if let Some(auth) = options.auth {
+        app.with(auth);
+    }
+    // Audit before checking roles, so that denied requests show up as well.
+    if let Some(log) = options.audit.clone() {
+        app.with(AuditMiddleware::new(log));
+    }
+    if let Some(roles) = options.roles {
+        app.with(roles::Authorize::new(roles));
+    }
+    route(&mut app, "", "/health").get(health_handler);
+    route(&mut app, "", "/daemons").get(daemons_handler);
+    route(&mut app, "", "/metrics")
+        .get(move |req| metrics::metrics_handler(metrics.clone(), req));
+    if let Some(log) = options.audit {
+        route(&mut app, "", "/audit")
+            .get(move |req| audit::audit_handler(log.clone(), req));
+    }
+    if let Some(archive) = options.archive {
+        for (name, state) in &states {
+            archive::watch(archive.clone(), name.clone(), state.clone());
+        }
+        route(&mut app, "", "/history")
+            .get(move |req| archive::history_handler(archive.clone(), req));
+    }
+    if options.templates.is_some() {
+        route(&mut app, "", "/templates").get(templates::list_handler);
+        route(&mut app, "", "/templates/:name")
+            .get(templates::get_handler)
+            .put(templates::put_handler)
+            .delete(templates::delete_handler);
+    }
+    mount_daemon_routes(&mut app, "");
+    for (name, state) in states {
+        let mut daemon_app = tide::with_state(state);
+        mount_daemon_routes(&mut daemon_app, "/d/:daemon");
+        app.at(&format!("/d/{name}")).nest(daemon_app);
+    }
+    app
+}
+
+fn mount_daemon_routes(app: &mut tide::Server<AppState>, prefix: &str) {
+    route(app, prefix, "/status").get(status_handler);
+    route(app, prefix, "/analytics").get(analytics::analytics_handler);
+    route(app, prefix, "/events").get(tide::sse::endpoint(events_handler));
+    route(app, prefix, "/logs/:id").get(logs_handler);
+    route(app, prefix, "/logs/:id/stream").get(logs_stream_handler);
+    route(app, prefix, "/tasks").post(add_task_handler);
+    route(app, prefix, "/tasks/actions").post(bulk_action_handler);
+    route(app, prefix, "/tasks/batch").post(batch::batch_handler);
+    route(app, prefix, "/queue/switch").post(queue_switch_handler);
+    route(app, prefix, "/groups").post(group_handler);
+    route(app, prefix, "/clean").post(clean_handler);
+    route(app, prefix, "/config/callback")
         .get(callback_get_handler)
         .post(callback_update_handler);
-    app.at("/task/:id").post(task_action_handler);
-    app
+    route(app, prefix, "/task/:id")
+        .post(task_action_handler)
+        .put(edit_task_handler);
+    route(app, prefix, "/task/:id/edit")
+        .get(edit_lock_handler)
+        .delete(edit_restore_handler);
+    route(app, prefix, "/task/:id/input").post(task_input_handler);
+    route(app, prefix, "/task/:id/env").get(env_list_handler);
+    route(app, prefix, "/task/:id/env/:key")
+        .get(env_get_handler)
+        .put(env_set_handler)
+        .delete(env_unset_handler);
+    if app.state().templates.is_some() {
+        route(app, prefix, "/templates/:name/run").post(templates::run_handler);
+    }
+}
+
+/// `app.at(path)`, labeling the responses of the route as `prefix` + `path` in the metrics.
+fn route<'a>(
+    app: &'a mut tide::Server<AppState>,
+    prefix: &str,
+    path: &str,
+) -> tide::Route<'a, AppState> {
+    let mut route = app.at(path);
+    route.with(MatchedRoute::new(format!("{prefix}{path}")));
+    route
 }
 
 async fn health_handler(_: Request<AppState>) -> tide::Result {
@@ -49,57 +287,92 @@ async fn health_handler(_: Request<AppState>) -> tide::Result {
 }
 
 async fn status_handler(req: Request<AppState>) -> tide::Result {
+    match cached_status(req.state()).await {
+        Ok((mut entry, cached)) => {
+            mask_state_envs(&mut entry.payload);
+            let mut body = json!({
+                "ok": true,
+                "status": entry.payload,
+                "stats": entry.stats,
+                "digest": entry.digest,
+            });
+            if cached {
+                body["cached"] = json!(true);
+            }
+            json_response(StatusCode::Ok, body)
+        }
+        Err(error) => error_response(error),
+    }
+}
+
+/// Get the daemon's status, served from the status cache if it's fresh enough.
+/// The second value indicates whether the cache has been hit.
+async fn cached_status(state: &AppState) -> Result<(StatusCacheEntry, bool)> {
     const CACHE_TTL: Duration = Duration::from_millis(500);
     {
-        let cache = req.state().status_cache.lock().map_err(|_| {
-            tide::Error::from_str(StatusCode::InternalServerError, "Status cache lock failed")
-        })?;
+        let cache = state
+            .status_cache
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Status cache lock failed"))?;
 
         if let Some(entry) = cache.value.as_ref() {
             if entry.at.elapsed() <= CACHE_TTL {
-                return json_response(
-                    StatusCode::Ok,
-                    json!({
-                        "ok": true,
-                        "status": entry.payload.clone(),
-                        "cached": true,
-                        "stats": entry.stats.clone(),
-                        "digest": entry.digest.clone(),
-                    }),
-                );
+                return Ok((entry.clone(), true));
             }
         }
     }
 
-    match req.state().backend.status().await {
-        Ok(status) => {
-            let (stats, digest) = compute_group_stats(&status);
-            if let Ok(mut cache) = req.state().status_cache.lock() {
-                cache.value = Some(StatusCacheEntry {
-                    at: Instant::now(),
-                    payload: status.clone(),
-                    stats: stats.clone(),
-                    digest: digest.clone(),
-                });
-            }
-            json_response(
-                StatusCode::Ok,
-                json!({
-                    "ok": true,
-                    "status": status,
-                    "stats": stats,
-                    "digest": digest,
-                }),
-            )
-        }
-        Err(error) => json_response(
-            StatusCode::InternalServerError,
-            json!({
-                "ok": false,
-                "error": error.to_string(),
+    let status = state.backend.status().await?;
+    let (stats, digest) = compute_group_stats(&status);
+    let entry = StatusCacheEntry {
+        at: Instant::now(),
+        payload: status,
+        stats,
+        digest,
+    };
+    if let Ok(mut cache) = state.status_cache.lock() {
+        cache.value = Some(entry.clone());
+    }
+    Ok((entry, false))
+}
+
+async fn daemons_handler(req: Request<AppState>) -> tide::Result {
+    let mut daemons = Vec::new();
+    for (name, state) in req.state().daemons.iter() {
+        let overview = match cached_status(state).await {
+            Ok((entry, _)) => json!({
+                "name": name,
+                "ok": true,
+                "tasks": entry.payload.tasks.len(),
+                "stats": entry.stats,
+                "digest": entry.digest,
             }),
-        ),
+            Err(error) => {
+                let mut body = ApiError::from(error).body();
+                body["name"] = json!(name);
+                body
+            }
+        };
+        daemons.push(overview);
     }
+
+    json_response(
+        StatusCode::Ok,
+        json!({
+            "ok": true,
+            "daemons": daemons,
+        }),
+    )
+}
+
+async fn events_handler(req: Request<AppState>, sender: tide::sse::Sender) -> tide::Result<()> {
+    let events = EventHub::subscribe(&req.state().events, req.state());
+    while let Ok(event) = events.recv().await {
+        sender
+            .send(event.name(), events::event_data(&event), None)
+            .await?;
+    }
+    Ok(())
 }
 
 #[derive(Deserialize)]
@@ -108,76 +381,270 @@ struct CallbackConfigRequest {
     callback_log_lines: Option<usize>,
 }
 
-async fn callback_get_handler(_: Request<AppState>) -> tide::Result {
-    let config_path = config_path_override();
-    let (settings, found) = Settings::read(&config_path)
-        .map_err(|err| tide::Error::from_str(StatusCode::InternalServerError, err.to_string()))?;
+/// The daemon settings of `/config/callback`, which are those of the profile if the
+/// daemon runs with one.
+fn callback_settings<'a>(
+    settings: &'a mut Settings,
+    profile: Option<&str>,
+) -> Result<&'a mut pueue_lib::settings::Daemon, ApiError> {
+    match profile {
+        None => Ok(&mut settings.daemon),
+        Some(profile) => settings
+            .profiles
+            .get_mut(profile)
+            .map(|nested| &mut nested.daemon)
+            .ok_or_else(|| ApiError::NotFound(format!("Profile {profile} doesn't exist"))),
+    }
+}
+
+async fn callback_get_handler(req: Request<AppState>) -> tide::Result {
+    let config_path = req.state().config_path.clone();
+    let profile = req.state().profile.as_deref();
+    let (mut settings, found) = Settings::read(&config_path)
+        .map_err(ApiError::from)?;
+    let daemon = callback_settings(&mut settings, profile)?;
 
     json_response(
         StatusCode::Ok,
         json!({
             "ok": true,
             "config": {
-                "callback": settings.daemon.callback,
-                "callback_log_lines": settings.daemon.callback_log_lines,
+                "callback": daemon.callback,
+                "callback_log_lines": daemon.callback_log_lines,
                 "found": found,
                 "config_path": config_path.as_ref().map(|path| path.display().to_string()),
+                "profile": profile,
             }
         }),
     )
 }
 
 async fn callback_update_handler(mut req: Request<AppState>) -> tide::Result {
-    let config_path = config_path_override();
+    let config_path = req.state().config_path.clone();
     let body: CallbackConfigRequest = req.body_json().await.map_err(|_| {
         tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
     })?;
 
+    let profile = req.state().profile.as_deref();
     let (mut settings, _found) = Settings::read(&config_path)
-        .map_err(|err| tide::Error::from_str(StatusCode::InternalServerError, err.to_string()))?;
+        .map_err(ApiError::from)?;
+    let daemon = callback_settings(&mut settings, profile)?;
 
     if let Some(callback) = body.callback {
         let trimmed = callback.trim().to_string();
         if trimmed.is_empty() {
-            settings.daemon.callback = None;
+            daemon.callback = None;
         } else {
-            settings.daemon.callback = Some(trimmed);
+            daemon.callback = Some(trimmed);
         }
     }
 
     if let Some(lines) = body.callback_log_lines {
-        settings.daemon.callback_log_lines = lines;
+        daemon.callback_log_lines = lines;
     }
+    let (callback, callback_log_lines) = (daemon.callback.clone(), daemon.callback_log_lines);
 
     settings
         .save(&config_path)
-        .map_err(|err| tide::Error::from_str(StatusCode::InternalServerError, err.to_string()))?;
+        .map_err(ApiError::from)?;
 
     json_response(
         StatusCode::Ok,
         json!({
             "ok": true,
             "config": {
-                "callback": settings.daemon.callback,
-                "callback_log_lines": settings.daemon.callback_log_lines,
+                "callback": callback,
+                "callback_log_lines": callback_log_lines,
                 "config_path": config_path.as_ref().map(|path| path.display().to_string()),
+                "profile": profile,
             }
         }),
     )
 }
 
-#[derive(Deserialize)]
-struct TaskActionRequest {
-    action: String,
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TaskActionRequest {
+    pub action: String,
+    /// Only for `stash` and `enqueue`: when the task should be enqueued.
+    /// See [`parse_enqueue_at`] for the accepted formats.
+    pub enqueue_at: Option<String>,
+    /// Only for `kill`: the signal to send instead of SIGKILL, by name (`SIGTERM`, `term`)
+    /// or number (`15`).
+    pub signal: Option<String>,
+    /// Only for `kill`: send SIGTERM and only escalate to SIGKILL for tasks that are still
+    /// alive after the grace period.
+    pub graceful: Option<bool>,
+    /// Grace period of a graceful kill. Defaults to `PUEUE_WEBUI_KILL_GRACE_SECS`.
+    pub grace_secs: Option<u64>,
+    #[serde(flatten)]
+    pub restart: RestartOptions,
+}
+
+/// Changes for the `restart` action. Unset fields keep the values of the finished task.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RestartOptions {
+    pub command: Option<String>,
+    pub path: Option<String>,
+    /// An empty label removes it.
+    pub label: Option<String>,
+    pub priority: Option<i32>,
+    pub stashed: Option<bool>,
+    /// Defaults to `true`, unless the task is restarted as stashed.
+    pub start_immediately: Option<bool>,
+    /// Reuse the finished task instead of adding a new one.
+    /// Defaults to the daemon's `client.restart_in_place` setting.
+    pub in_place: Option<bool>,
+}
+
+impl RestartOptions {
+    /// Whether any field of the restarted task is changed.
+    pub fn changes_task(&self) -> bool {
+        self.command.is_some() || self.path.is_some() || self.label.is_some() || self.priority.is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.changes_task()
+            && self.stashed.is_none()
+            && self.start_immediately.is_none()
+            && self.in_place.is_none()
+    }
+
+    pub fn apply(&self, task: &mut TaskToRestart) {
+        if let Some(command) = self.command.as_ref() {
+            task.original_command = command.clone();
+        }
+        if let Some(path) = self.path.as_ref() {
+            task.path = PathBuf::from(path);
+        }
+        if let Some(label) = self.label.as_ref() {
+            task.label = (!label.is_empty()).then(|| label.clone());
+        }
+        if let Some(priority) = self.priority {
+            task.priority = priority;
+        }
+    }
+
+    pub fn stashed(&self) -> bool {
+        self.stashed.unwrap_or(false)
+    }
+
+    pub fn start_immediately(&self) -> bool {
+        self.start_immediately.unwrap_or(!self.stashed())
+    }
+}
+
+impl TaskActionRequest {
+    /// The grace period, if this is a graceful kill.
+    fn grace_period(&self) -> Option<Duration> {
+        if !self.graceful.unwrap_or(false) {
+            return None;
+        }
+        Some(
+            self.grace_secs
+                .map(Duration::from_secs)
+                .unwrap_or_else(kill_grace_period),
+        )
+    }
+
+    /// Validate the action's options and resolve relative times.
+    fn normalize(&mut self) -> tide::Result<()> {
+        if !self.restart.is_empty() && self.action != "restart" {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                "Task changes are only supported for restart",
+            ));
+        }
+        if let Some(command) = self.restart.command.as_deref() {
+            if command.trim().is_empty() {
+                return Err(tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    "Command cannot be empty",
+                ));
+            }
+        }
+        let kill_options =
+            self.signal.is_some() || self.graceful.is_some() || self.grace_secs.is_some();
+        if kill_options && self.action != "kill" {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                "signal, graceful and grace_secs are only supported for kill",
+            ));
+        }
+        if self.graceful.unwrap_or(false) {
+            if self.signal.is_some() {
+                return Err(tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    "A graceful kill always starts with SIGTERM",
+                ));
+            }
+            self.signal = Some(Signal::SigTerm.to_string());
+        }
+        if let Some(signal) = self.signal.as_deref() {
+            let signal = Signal::from_str(signal.trim()).map_err(|_| {
+                tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    format!("Unknown signal: {signal}"),
+                )
+            })?;
+            self.signal = Some(signal.to_string());
+        }
+
+        if let Some(enqueue_at) = self.enqueue_at.as_deref() {
+            if !matches!(self.action.as_str(), "stash" | "enqueue") {
+                return Err(tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    "enqueue_at is only supported for stash and enqueue",
+                ));
+            }
+            let at = parse_enqueue_at(enqueue_at)
+                .map_err(|err| tide::Error::from_str(StatusCode::BadRequest, err))?;
+            self.enqueue_at = Some(at.to_rfc3339());
+        }
+        Ok(())
+    }
 }
 
 async fn task_action_handler(mut req: Request<AppState>) -> tide::Result {
     let task_id = parse_task_id(&req)?;
-    let body: TaskActionRequest = req.body_json().await.map_err(|_| {
+    let mut body: TaskActionRequest = req.body_json().await.map_err(|_| {
         tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
     })?;
+    body.normalize()?;
 
-    match req.state().backend.action(task_id, &body.action).await {
+    let result = match req.state().backend.action(task_id, &body).await {
+        Ok(result) => match body.grace_period() {
+            Some(grace) => escalate_kill(req.state(), vec![task_id], grace)
+                .await
+                .map(|escalated| json!({ "ok": true, "result": result, "escalated": escalated })),
+            None => Ok(json!({ "ok": true, "result": result })),
+        },
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(body) => json_response(StatusCode::Ok, body),
+        Err(error) => error_response(error),
+    }
+}
+
+#[derive(Deserialize)]
+struct TaskInputRequest {
+    input: String,
+    /// Terminate the input with a newline, like pressing enter. Defaults to `true`.
+    newline: Option<bool>,
+}
+
+async fn task_input_handler(mut req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    let body: TaskInputRequest = req.body_json().await.map_err(|_| {
+        tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
+    })?;
+
+    let mut input = body.input;
+    if body.newline.unwrap_or(true) && !input.ends_with('\n') {
+        input.push('\n');
+    }
+
+    match req.state().backend.send_input(task_id, input).await {
         Ok(result) => json_response(
             StatusCode::Ok,
             json!({
@@ -185,41 +652,641 @@ async fn task_action_handler(mut req: Request<AppState>) -> tide::Result {
                 "result": result,
             }),
         ),
-        Err(error) => json_response(
-            StatusCode::InternalServerError,
+        Err(error) => error_response(error),
+    }
+}
+
+/// Placeholder for values of secret-looking environment variables.
+pub(crate) const MASKED_VALUE: &str = "********";
+
+/// Whether an environment variable probably contains a secret and must not be shown.
+pub(crate) fn is_secret_env(key: &str) -> bool {
+    const MARKERS: [&str; 8] = [
+        "SECRET",
+        "TOKEN",
+        "PASSWORD",
+        "PASSWD",
+        "PRIVATE",
+        "CREDENTIAL",
+        "AUTH",
+        "API_KEY",
+    ];
+    let key = key.to_ascii_uppercase();
+    key.ends_with("_KEY") || MARKERS.iter().any(|marker| key.contains(marker))
+}
+
+/// Replace the values of secret environment variables, before they're sent to clients.
+pub(crate) fn mask_envs<'a>(envs: impl IntoIterator<Item = (&'a String, &'a mut String)>) {
+    for (key, value) in envs {
+        if is_secret_env(key) {
+            *value = MASKED_VALUE.to_string();
+        }
+    }
+}
+
+/// Mask every `envs` object in a JSON value, including nested ones like `tasks[].envs`
+/// or `task.envs`. For bodies whose layout isn't known, like the CLI's output.
+pub(crate) fn mask_json_envs(value: &mut serde_json::Value) {
+    use serde_json::Value;
+
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::Object(envs) if key == "envs" => {
+                        for (name, value) in envs.iter_mut() {
+                            if is_secret_env(name) {
+                                *value = Value::String(MASKED_VALUE.to_string());
+                            }
+                        }
+                    }
+                    _ => mask_json_envs(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(mask_json_envs),
+        _ => {}
+    }
+}
+
+/// Mask the environment variables of all tasks, see [`mask_envs`].
+pub(crate) fn mask_state_envs(state: &mut State) {
+    for task in state.tasks.values_mut() {
+        mask_envs(&mut task.envs);
+    }
+}
+
+fn masked_env_value(key: &str, value: &str) -> String {
+    if is_secret_env(key) {
+        MASKED_VALUE.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn parse_env_key(req: &Request<AppState>) -> tide::Result<String> {
+    let key = req.param("key")?.to_string();
+    if key.is_empty() || key.contains('=') || key.contains('\0') {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            "Invalid environment variable name",
+        ));
+    }
+    Ok(key)
+}
+
+async fn env_list_handler(req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    let task = match cached_status(req.state()).await {
+        Ok((entry, _)) => entry.payload.tasks.get(&task_id).cloned(),
+        Err(error) => return error_response(error),
+    };
+    let Some(task) = task else {
+        return Err(tide::Error::from_str(StatusCode::NotFound, "Task not found"));
+    };
+
+    let envs: BTreeMap<&String, String> = task
+        .envs
+        .iter()
+        .map(|(key, value)| (key, masked_env_value(key, value)))
+        .collect();
+    json_response(
+        StatusCode::Ok,
+        json!({
+            "ok": true,
+            "envs": envs,
+        }),
+    )
+}
+
+async fn env_get_handler(req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    let key = parse_env_key(&req)?;
+    let value = match cached_status(req.state()).await {
+        Ok((entry, _)) => entry
+            .payload
+            .tasks
+            .get(&task_id)
+            .map(|task| task.envs.get(&key).cloned()),
+        Err(error) => return error_response(error),
+    };
+
+    match value {
+        None => Err(tide::Error::from_str(StatusCode::NotFound, "Task not found")),
+        Some(None) => Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            "Environment variable not set",
+        )),
+        Some(Some(value)) => json_response(
+            StatusCode::Ok,
             json!({
-                "ok": false,
-                "error": error.to_string(),
+                "ok": true,
+                "key": key,
+                "value": masked_env_value(&key, &value),
+                "masked": is_secret_env(&key),
             }),
         ),
     }
 }
 
-async fn logs_handler(req: Request<AppState>) -> tide::Result {
+#[derive(Deserialize)]
+struct EnvSetRequest {
+    value: String,
+}
+
+/// Environment variables can only be changed as long as the task hasn't been started.
+async fn ensure_env_editable(state: &AppState, task_id: usize) -> tide::Result<()> {
+    let status = state
+        .backend
+        .status()
+        .await
+        .map_err(ApiError::from)?;
+    match status.tasks.get(&task_id).map(|task| &task.status) {
+        None => Err(tide::Error::from_str(StatusCode::NotFound, "Task not found")),
+        Some(TaskStatus::Queued { .. } | TaskStatus::Stashed { .. }) => Ok(()),
+        Some(_) => Err(tide::Error::from_str(
+            StatusCode::Conflict,
+            "Environment variables can only be changed for queued or stashed tasks",
+        )),
+    }
+}
+
+async fn env_set_handler(mut req: Request<AppState>) -> tide::Result {
     let task_id = parse_task_id(&req)?;
-    let lines = req
-        .url()
-        .query_pairs()
-        .find(|(key, _)| key == "lines")
-        .and_then(|(_, value)| value.parse::<usize>().ok());
-    match req.state().backend.logs(task_id, lines).await {
-        Ok(logs) => json_response(
+    let key = parse_env_key(&req)?;
+    let body: EnvSetRequest = req.body_json().await.map_err(|_| {
+        tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
+    })?;
+    ensure_env_editable(req.state(), task_id).await?;
+
+    let masked = masked_env_value(&key, &body.value);
+    match req.state().backend.set_env(task_id, key.clone(), body.value).await {
+        Ok(result) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "key": key,
+                "value": masked,
+                "result": result,
+            }),
+        ),
+        Err(error) => error_response(error),
+    }
+}
+
+async fn env_unset_handler(req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    let key = parse_env_key(&req)?;
+    ensure_env_editable(req.state(), task_id).await?;
+
+    match req.state().backend.unset_env(task_id, key).await {
+        Ok(result) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "result": result,
+            }),
+        ),
+        Err(error) => error_response(error),
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EditTaskRequest {
+    pub command: Option<String>,
+    pub path: Option<String>,
+    /// An empty label removes the task's label.
+    pub label: Option<String>,
+    pub priority: Option<i32>,
+}
+
+impl EditTaskRequest {
+    fn apply(self, task: &mut EditableTask) -> Result<(), &'static str> {
+        if let Some(command) = self.command {
+            let command = command.trim();
+            if command.is_empty() {
+                return Err("Command must not be empty");
+            }
+            task.original_command = command.to_string();
+        }
+        if let Some(path) = self.path {
+            if path.trim().is_empty() {
+                return Err("Path must not be empty");
+            }
+            task.path = PathBuf::from(path);
+        }
+        if let Some(label) = self.label {
+            let label = label.trim();
+            task.label = (!label.is_empty()).then(|| label.to_string());
+        }
+        if let Some(priority) = self.priority {
+            task.priority = priority;
+        }
+        Ok(())
+    }
+}
+
+async fn edit_lock_handler(req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    let state = req.state();
+
+    // Reopening the editor for a task we already locked just extends the lock.
+    let locked = match state.edits.get(task_id) {
+        Some(task) => Ok(task),
+        None => state.backend.edit_request(task_id).await,
+    };
+    match locked {
+        Ok(task) => {
+            EditSessions::start(&state.edits, state.backend.clone(), task.clone());
+            json_response(
+                StatusCode::Ok,
+                json!({
+                    "ok": true,
+                    "task": task,
+                    "expires_in_secs": state.edits.timeout().as_secs(),
+                }),
+            )
+        }
+        Err(error) => error_response(error),
+    }
+}
+
+async fn edit_restore_handler(req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    req.state().edits.finish(task_id);
+    match req.state().backend.edit_restore(task_id).await {
+        Ok(result) => json_response(
             StatusCode::Ok,
             json!({
                 "ok": true,
-                "log": logs,
+                "result": result,
             }),
         ),
-        Err(error) => json_response(
-            StatusCode::InternalServerError,
+        Err(error) => error_response(error),
+    }
+}
+
+async fn edit_task_handler(mut req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    let body: EditTaskRequest = req.body_json().await.map_err(|_| {
+        tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
+    })?;
+
+    // Run detached from the request, so an aborted request can't leave the task locked
+    // between the individual steps.
+    let state = req.state().clone();
+    let result = async_std::task::spawn(async move {
+        let (mut task, locked_here) = match state.edits.finish(task_id) {
+            Some(task) => (task, false),
+            None => (state.backend.edit_request(task_id).await?, true),
+        };
+        if let Err(message) = body.apply(&mut task) {
+            if locked_here {
+                state.backend.edit_restore(task_id).await?;
+            } else {
+                EditSessions::start(&state.edits, state.backend.clone(), task);
+            }
+            return Ok(Err(message));
+        }
+        state.backend.edit_submit(task).await.map(Ok)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(result)) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "result": result,
+            }),
+        ),
+        Ok(Err(message)) => Err(tide::Error::from_str(StatusCode::BadRequest, message)),
+        Err(error) => error_response(error),
+    }
+}
+
+/// The tasks a bulk action applies to.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkSelection {
+    Ids(Vec<usize>),
+    Group(String),
+    All,
+}
+
+impl From<BulkSelection> for TaskSelection {
+    fn from(selection: BulkSelection) -> Self {
+        match selection {
+            BulkSelection::Ids(ids) => TaskSelection::TaskIds(ids),
+            BulkSelection::Group(group) => TaskSelection::Group(group),
+            BulkSelection::All => TaskSelection::All,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BulkActionRequest {
+    pub selection: BulkSelection,
+    #[serde(flatten)]
+    pub request: TaskActionRequest,
+}
+
+async fn bulk_action_handler(mut req: Request<AppState>) -> tide::Result {
+    let mut body: BulkActionRequest = req.body_json().await.map_err(|_| {
+        tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
+    })?;
+    let action = body.request.action.clone();
+    if !matches!(
+        action.as_str(),
+        "start" | "resume" | "pause" | "kill" | "remove" | "restart" | "stash" | "enqueue"
+    ) {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!("Unsupported action: {action}"),
+        ));
+    }
+    body.request.normalize()?;
+
+    let state = match req.state().backend.status().await {
+        Ok(state) => state,
+        Err(error) => return error_response(error),
+    };
+
+    let ids: Vec<usize> = match &body.selection {
+        BulkSelection::Ids(ids) => ids.clone(),
+        BulkSelection::Group(group) => {
+            if !state.groups.contains_key(group) {
+                return error_response(ApiError::NotFound(format!("Group {group} doesn't exist")));
+            }
+            state.task_ids_in_group(group)
+        }
+        BulkSelection::All => state.tasks.keys().copied().collect(),
+    };
+
+    // Decide upfront which tasks the action applies to, so every task gets an outcome.
+    let mut outcomes = BTreeMap::new();
+    let mut eligible = Vec::new();
+    for id in ids {
+        match state.tasks.get(&id) {
+            None => {
+                outcomes.insert(id, json!({ "id": id, "outcome": "not_found" }));
+            }
+            Some(task) => match action_applies(&action, &task.status) {
+                Ok(()) => eligible.push(id),
+                Err(reason) => {
+                    outcomes.insert(
+                        id,
+                        json!({ "id": id, "outcome": "skipped", "reason": reason }),
+                    );
+                }
+            },
+        }
+    }
+
+    if eligible.is_empty() {
+        return json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "result": null,
+                "outcomes": outcomes.into_values().collect::<Vec<_>>(),
+            }),
+        );
+    }
+
+    // Group and "all" selections are forwarded as-is for start/pause/kill, so the
+    // daemon also (un)pauses the group itself, just like the pueue CLI does.
+    // Explicit ids are narrowed down to the eligible ones, matching the outcomes.
+    let selection = match (action.as_str(), &body.selection) {
+        (
+            "start" | "resume" | "pause" | "kill",
+            BulkSelection::Group(_) | BulkSelection::All,
+        ) => body.selection.clone(),
+        _ => BulkSelection::Ids(eligible.clone()),
+    };
+
+    let (status, mut response, outcome, error) =
+        match req.state().backend.bulk_action(selection, &body.request).await {
+            Ok(result) => (
+                StatusCode::Ok,
+                json!({ "ok": true, "result": result }),
+                "ok",
+                None,
+            ),
+            Err(error) => {
+                let error = ApiError::from(error);
+                (error.status(), error.body(), "failed", Some(error.to_string()))
+            }
+        };
+    let (status, escalated) = match body.request.grace_period() {
+        Some(grace) if error.is_none() => {
+            match escalate_kill(req.state(), eligible.clone(), grace).await {
+                Ok(escalated) => (status, escalated),
+                Err(error) => {
+                    let error = ApiError::from(error);
+                    response = error.body();
+                    (error.status(), Vec::new())
+                }
+            }
+        }
+        _ => (status, Vec::new()),
+    };
+    for id in eligible {
+        let mut entry = json!({ "id": id, "outcome": outcome });
+        if let Some(error) = error.as_ref() {
+            entry["reason"] = json!(error);
+        }
+        if escalated.contains(&id) {
+            entry["escalated"] = json!(true);
+        }
+        outcomes.insert(id, entry);
+    }
+    response["outcomes"] = json!(outcomes.into_values().collect::<Vec<_>>());
+    json_response(status, response)
+}
+
+/// How long a graceful kill waits for tasks to exit, if the request doesn't say.
+fn kill_grace_period() -> Duration {
+    let secs = std::env::var("PUEUE_WEBUI_KILL_GRACE_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(10);
+    Duration::from_secs(secs)
+}
+
+/// Wait up to `grace` for SIGTERMed tasks to exit and SIGKILL the remaining ones.
+///
+/// Returns the ids of the tasks that had to be killed forcefully.
+async fn escalate_kill(state: &AppState, ids: Vec<usize>, grace: Duration) -> Result<Vec<usize>> {
+    let deadline = Instant::now() + grace;
+    loop {
+        let status = state.backend.status().await?;
+        let alive: Vec<usize> = ids
+            .iter()
+            .copied()
+            .filter(|id| {
+                matches!(
+                    status.tasks.get(id).map(|task| &task.status),
+                    Some(TaskStatus::Running { .. } | TaskStatus::Paused { .. })
+                )
+            })
+            .collect();
+        if alive.is_empty() {
+            return Ok(alive);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            let request = TaskActionRequest {
+                action: "kill".to_string(),
+                signal: Some(Signal::SigKill.to_string()),
+                ..Default::default()
+            };
+            state
+                .backend
+                .bulk_action(BulkSelection::Ids(alive.clone()), &request)
+                .await?;
+            return Ok(alive);
+        }
+        async_std::task::sleep((deadline - now).min(Duration::from_millis(250))).await;
+    }
+}
+
+/// Check whether an action can be applied to a task in the given status.
+fn action_applies(action: &str, status: &TaskStatus) -> Result<(), &'static str> {
+    match (action, status) {
+        (
+            "start" | "resume",
+            TaskStatus::Queued { .. } | TaskStatus::Stashed { .. } | TaskStatus::Paused { .. },
+        ) => Ok(()),
+        ("start" | "resume", _) => Err("Task isn't queued, stashed or paused"),
+        ("pause", TaskStatus::Running { .. }) => Ok(()),
+        ("pause", _) => Err("Task isn't running"),
+        ("kill", TaskStatus::Running { .. } | TaskStatus::Paused { .. }) => Ok(()),
+        ("kill", _) => Err("Task isn't running or paused"),
+        (
+            "remove",
+            TaskStatus::Running { .. } | TaskStatus::Paused { .. } | TaskStatus::Locked { .. },
+        ) => Err("Task is running or being edited"),
+        ("remove", _) => Ok(()),
+        ("restart", TaskStatus::Done { .. }) => Ok(()),
+        ("restart", _) => Err("Task hasn't finished yet"),
+        ("stash", TaskStatus::Queued { .. } | TaskStatus::Locked { .. }) => Ok(()),
+        ("stash", _) => Err("Task isn't queued"),
+        ("enqueue", TaskStatus::Stashed { .. }) => Ok(()),
+        ("enqueue", _) => Err("Task isn't stashed"),
+        _ => Err("Unsupported action"),
+    }
+}
+
+#[derive(Deserialize)]
+struct QueueSwitchRequest {
+    task_id_1: usize,
+    task_id_2: usize,
+}
+
+async fn queue_switch_handler(mut req: Request<AppState>) -> tide::Result {
+    let body: QueueSwitchRequest = req.body_json().await.map_err(|_| {
+        tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
+    })?;
+    if body.task_id_1 == body.task_id_2 {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            "Can't switch a task with itself",
+        ));
+    }
+
+    let state = match req.state().backend.status().await {
+        Ok(state) => state,
+        Err(error) => return error_response(error),
+    };
+    for id in [body.task_id_1, body.task_id_2] {
+        match state.tasks.get(&id).map(|task| &task.status) {
+            None => {
+                return error_response(ApiError::NotFound(format!("Task {id} doesn't exist")))
+            }
+            Some(TaskStatus::Queued { .. } | TaskStatus::Stashed { .. }) => {}
+            Some(_) => {
+                return error_response(ApiError::Conflict(format!("Task {id} isn't queued or stashed")))
+            }
+        }
+    }
+
+    match req
+        .state()
+        .backend
+        .switch_tasks(body.task_id_1, body.task_id_2)
+        .await
+    {
+        Ok(result) => json_response(
+            StatusCode::Ok,
             json!({
-                "ok": false,
-                "error": error.to_string(),
+                "ok": true,
+                "result": result,
             }),
         ),
+        Err(error) => error_response(error),
     }
 }
 
+async fn logs_handler(req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    let lines = parse_lines(&req);
+    match req.state().backend.logs(task_id, lines).await {
+        Ok(mut logs) => {
+            // The daemon and the CLI both send the task along, with its environment.
+            mask_json_envs(&mut logs);
+            json_response(
+                StatusCode::Ok,
+                json!({
+                    "ok": true,
+                    "log": logs,
+                }),
+            )
+        }
+        Err(error) => error_response(error),
+    }
+}
+
+async fn logs_stream_handler(req: Request<AppState>) -> tide::Result {
+    let task_id = parse_task_id(&req)?;
+    let lines = parse_lines(&req);
+    let stream = match req.state().backend.stream_logs(task_id, lines).await {
+        Ok(stream) => stream,
+        Err(error) => return error_response(error),
+    };
+
+    Ok(tide::sse::upgrade(req, move |_req, sender| {
+        let stream = stream.clone();
+        async move {
+            while let Ok(event) = stream.recv().await {
+                match event {
+                    LogStreamEvent::Chunk(output) => {
+                        let data = json!({ "task_id": task_id, "output": output });
+                        sender.send("log", data.to_string(), None).await?;
+                    }
+                    LogStreamEvent::Error(error) => {
+                        let data = json!({ "task_id": task_id, "error": error });
+                        sender.send("error", data.to_string(), None).await?;
+                        break;
+                    }
+                }
+            }
+            sender
+                .send("close", json!({ "task_id": task_id }).to_string(), None)
+                .await?;
+            Ok(())
+        }
+    }))
+}
+
+fn parse_lines(req: &Request<AppState>) -> Option<usize> {
+    req.url()
+        .query_pairs()
+        .find(|(key, _)| key == "lines")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+}
+
 fn parse_task_id(req: &Request<AppState>) -> tide::Result<usize> {
     let id: String = req.param("id")?.to_string();
     id.parse::<usize>().map_err(|_| {
@@ -227,6 +1294,20 @@ fn parse_task_id(req: &Request<AppState>) -> tide::Result<usize> {
     })
 }
 
+/// Parse the JSON body without consuming it, so the handler can still read it.
+pub(crate) async fn peek_json<T: serde::de::DeserializeOwned>(
+    req: &mut Request<AppState>,
+) -> tide::Result<Option<T>> {
+    let content_type = req.header("Content-Type").map(|values| values.last().to_string());
+    let bytes = req.body_bytes().await?;
+    let value = serde_json::from_slice(&bytes).ok();
+    req.set_body(bytes);
+    if let Some(content_type) = content_type {
+        req.insert_header("Content-Type", content_type);
+    }
+    Ok(value)
+}
+
 fn config_path_override() -> Option<PathBuf> {
     std::env::var("PUEUE_CONFIG").ok().map(PathBuf::from)
 }
@@ -240,18 +1321,61 @@ pub struct AddTaskRequest {
     pub priority: Option<i32>,
     pub label: Option<String>,
     pub path: Option<String>,
+    pub envs: Option<HashMap<String, String>>,
+    /// Ids of tasks that have to finish successfully before this one starts.
+    pub dependencies: Option<Vec<usize>>,
+    /// See [`parse_enqueue_at`] for the accepted formats.
+    pub enqueue_at: Option<String>,
+}
+
+/// Parse a point in time to enqueue a task at.
+///
+/// Accepts RFC3339 timestamps and offsets relative to now, such as `+90s`, `+30m`,
+/// `+2h`, `+1d` or combinations like `+1h30m`.
+pub fn parse_enqueue_at(value: &str) -> std::result::Result<DateTime<Local>, String> {
+    let value = value.trim();
+    let Some(offset) = value.strip_prefix('+') else {
+        return DateTime::parse_from_rfc3339(value)
+            .map(|at| at.with_timezone(&Local))
+            .map_err(|err| format!("Invalid timestamp '{value}': {err}"));
+    };
+
+    let invalid = || format!("Invalid relative time '{value}', expected e.g. +30m or +2h");
+    let mut seconds: i64 = 0;
+    let mut digits = String::new();
+    for char in offset.chars() {
+        if char.is_ascii_digit() {
+            digits.push(char);
+            continue;
+        }
+        let unit = match char {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let amount: i64 = digits.parse().map_err(|_| invalid())?;
+        seconds = amount
+            .checked_mul(unit)
+            .and_then(|amount| seconds.checked_add(amount))
+            .ok_or_else(invalid)?;
+        digits.clear();
+    }
+    if !digits.is_empty() || offset.is_empty() {
+        return Err(invalid());
+    }
+
+    chrono::TimeDelta::try_seconds(seconds)
+        .and_then(|delta| Local::now().checked_add_signed(delta))
+        .ok_or_else(invalid)
 }
 
 async fn add_task_handler(mut req: Request<AppState>) -> tide::Result {
-    let body: AddTaskRequest = req.body_json().await.map_err(|_| {
+    let mut body: AddTaskRequest = req.body_json().await.map_err(|_| {
         tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
     })?;
-    if body.command.trim().is_empty() {
-        return Err(tide::Error::from_str(
-            StatusCode::BadRequest,
-            "Missing command",
-        ));
-    }
+    prepare_add_task(req.state(), &mut body).await?;
 
     match req.state().backend.add_task(body).await {
         Ok(result) => json_response(
@@ -261,27 +1385,85 @@ async fn add_task_handler(mut req: Request<AppState>) -> tide::Result {
                 "result": result,
             }),
         ),
-        Err(error) => json_response(
-            StatusCode::InternalServerError,
-            json!({
-                "ok": false,
-                "error": error.to_string(),
-            }),
-        ),
+        Err(error) => error_response(error),
+    }
+}
+
+/// Check a task before it's added and resolve relative times, so that they don't
+/// drift while the request is being sent.
+pub(crate) async fn prepare_add_task(
+    state: &AppState,
+    body: &mut AddTaskRequest,
+) -> std::result::Result<(), ApiError> {
+    let status = if body.dependencies.as_ref().is_some_and(|ids| !ids.is_empty()) {
+        Some(state.backend.status().await.map_err(ApiError::from)?)
+    } else {
+        None
+    };
+    check_add_task(body, status.as_ref())
+}
+
+/// See [`prepare_add_task`]. Dependencies are checked against `status`, which is
+/// needed if there are any.
+pub(crate) fn check_add_task(
+    body: &mut AddTaskRequest,
+    status: Option<&State>,
+) -> std::result::Result<(), ApiError> {
+    if body.command.trim().is_empty() {
+        return Err(ApiError::BadRequest("Missing command".to_string()));
+    }
+
+    if let Some(enqueue_at) = body.enqueue_at.as_deref() {
+        let at = parse_enqueue_at(enqueue_at).map_err(ApiError::BadRequest)?;
+        body.enqueue_at = Some(at.to_rfc3339());
+    }
+
+    if let (Some(dependencies), Some(status)) = (body.dependencies.as_ref(), status) {
+        let missing: Vec<String> = dependencies
+            .iter()
+            .filter(|id| !status.tasks.contains_key(id))
+            .map(|id| id.to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(ApiError::BadRequest(format!(
+                "Unknown dependencies: {}",
+                missing.join(", ")
+            )));
+        }
     }
+    Ok(())
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct GroupActionRequest {
+    /// One of `add`, `remove`, `list`, `parallel`, `pause`, `start`/`resume` or `reset`.
     pub action: String,
     pub name: String,
+    /// For `add` and `parallel`. `0` means no limit.
     pub parallel_tasks: Option<usize>,
+    /// For `pause`: let running tasks finish instead of pausing them.
+    pub wait: Option<bool>,
 }
 
 async fn group_handler(mut req: Request<AppState>) -> tide::Result {
     let body: GroupActionRequest = req.body_json().await.map_err(|_| {
         tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
     })?;
+    if !matches!(
+        body.action.as_str(),
+        "add" | "remove" | "list" | "parallel" | "pause" | "start" | "resume" | "reset"
+    ) {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!("Unsupported group action: {}", body.action),
+        ));
+    }
+    if body.action == "parallel" && body.parallel_tasks.is_none() {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            "Missing parallel_tasks",
+        ));
+    }
 
     match req.state().backend.group_action(body).await {
         Ok(result) => json_response(
@@ -291,132 +1473,161 @@ async fn group_handler(mut req: Request<AppState>) -> tide::Result {
                 "result": result,
             }),
         ),
-        Err(error) => json_response(
-            StatusCode::InternalServerError,
+        Err(error) => error_response(error),
+    }
+}
+
+#[derive(Deserialize)]
+struct CleanTasksRequest {
+    #[serde(default)]
+    successful_only: bool,
+    group: Option<String>,
+    /// Only report which tasks would be removed.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// The ids of the tasks a clean would remove, following the daemon's rules:
+/// only finished tasks are removed, and never those an unfinished task depends on.
+pub fn clean_preview(state: &State, successful_only: bool, group: Option<&str>) -> Vec<usize> {
+    let condition = |task: &Task| match &task.status {
+        TaskStatus::Done { result, .. } => !successful_only || *result == TaskResult::Success,
+        _ => false,
+    };
+    let filtered = match group {
+        Some(group) => state.filter_tasks_of_group(condition, group),
+        None => state.filter_tasks(condition, None),
+    };
+
+    filtered
+        .matching_ids
+        .into_iter()
+        .filter(|id| {
+            !state
+                .tasks
+                .values()
+                .any(|task| !task.is_done() && task.dependencies.contains(id))
+        })
+        .collect()
+}
+
+async fn clean_handler(mut req: Request<AppState>) -> tide::Result {
+    let body: CleanTasksRequest = req.body_json().await.map_err(|_| {
+        tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body")
+    })?;
+
+    let state = match req.state().backend.status().await {
+        Ok(state) => state,
+        Err(error) => return error_response(error),
+    };
+    if let Some(group) = body.group.as_ref() {
+        if !state.groups.contains_key(group) {
+            return error_response(ApiError::NotFound(format!("Group {group} doesn't exist")));
+        }
+    }
+
+    let ids = clean_preview(&state, body.successful_only, body.group.as_deref());
+    if body.dry_run {
+        return json_response(
+            StatusCode::Ok,
             json!({
-                "ok": false,
-                "error": error.to_string(),
+                "ok": true,
+                "dry_run": true,
+                "count": ids.len(),
+                "ids": ids,
+            }),
+        );
+    }
+
+    match req
+        .state()
+        .backend
+        .clean(body.successful_only, body.group)
+        .await
+    {
+        Ok(result) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "dry_run": false,
+                "count": ids.len(),
+                "ids": ids,
+                "result": result,
             }),
         ),
+        Err(error) => error_response(error),
     }
 }
 
-fn json_response(status: StatusCode, value: serde_json::Value) -> tide::Result<Response> {
+/// The error body and status of a failed request.
+pub(crate) fn error_response(error: impl Into<ApiError>) -> tide::Result<Response> {
+    Ok(error.into().response())
+}
+
+pub(crate) fn json_response(status: StatusCode, value: serde_json::Value) -> tide::Result<Response> {
     let mut response = Response::new(status);
     response.set_body(tide::Body::from_json(&value)?);
     response.set_content_type(mime::JSON);
     Ok(response)
 }
 
-fn compute_group_stats(status: &serde_json::Value) -> (serde_json::Value, String) {
+fn compute_group_stats(state: &State) -> (serde_json::Value, String) {
     #[derive(Default)]
     struct GroupStats {
         total: u64,
         running: u64,
         queued: u64,
         paused: u64,
+        stashed: u64,
+        locked: u64,
         done: u64,
         success: u64,
         failed: u64,
+        results: BTreeMap<String, u64>,
         durations: Vec<f64>,
-        failed_ids: Vec<String>,
+        failed_ids: Vec<usize>,
     }
 
-    let mut stats: HashMap<String, GroupStats> = HashMap::new();
-    if let Some(groups) = status.get("groups").and_then(|v| v.as_object()) {
-        for name in groups.keys() {
-            stats.entry(name.clone()).or_default();
-        }
-    }
-    let empty_tasks = serde_json::Map::new();
-    let tasks = status
-        .get("tasks")
-        .and_then(|value| value.as_object())
-        .unwrap_or(&empty_tasks);
-    let mut task_keys: Vec<&String> = tasks.keys().collect();
-    task_keys.sort();
+    let mut stats: BTreeMap<String, GroupStats> = state
+        .groups
+        .keys()
+        .map(|name| (name.clone(), GroupStats::default()))
+        .collect();
 
     let mut hash: u64 = 5381;
-    let mut task_count: u64 = 0;
-
-    for id in task_keys {
-        let Some(task) = tasks.get(id) else { continue };
-        task_count += 1;
-        hash_str(&mut hash, id);
-        let group = task.get("group").and_then(|v| v.as_str()).unwrap_or("default");
-        let entry = stats.entry(group.to_string()).or_default();
-        entry.total += 1;
-
-        if let Some(command) = task.get("command") {
-            match command {
-                serde_json::Value::String(text) => hash_str(&mut hash, text),
-                serde_json::Value::Array(items) => {
-                    for item in items {
-                        if let Some(text) = item.as_str() {
-                            hash_str(&mut hash, text);
-                            hash_str(&mut hash, "|");
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
-        if let Some(label) = task.get("label").and_then(|v| v.as_str()) {
+    for (id, task) in &state.tasks {
+        hash_str(&mut hash, &id.to_string());
+        hash_str(&mut hash, &task.original_command);
+        if let Some(label) = task.label.as_ref() {
             hash_str(&mut hash, label);
         }
-        if let Some(path) = task.get("path").and_then(|v| v.as_str()) {
-            hash_str(&mut hash, path);
-        }
-        if let Some(priority) = task.get("priority") {
-            hash_str(&mut hash, &priority.to_string());
-        }
-        if let Some(group_name) = task.get("group").and_then(|v| v.as_str()) {
-            hash_str(&mut hash, group_name);
-        }
+        hash_str(&mut hash, &task.path.to_string_lossy());
+        hash_str(&mut hash, &task.priority.to_string());
+        hash_str(&mut hash, &task.group);
+        hash_str(&mut hash, &format!("{:?}", task.status));
 
-        if let Some(status_obj) = task.get("status").and_then(|v| v.as_object()) {
-            if let Some((key, detail)) = status_obj.iter().next() {
-                hash_str(&mut hash, key);
-                if let Some(detail_obj) = detail.as_object() {
-                    for field in ["start", "end", "enqueued_at", "result"] {
-                        if let Some(text) = detail_obj.get(field).and_then(|v| v.as_str()) {
-                            hash_str(&mut hash, text);
-                        }
-                    }
-                }
-                match key.as_str() {
-                    "Running" => {
-                        entry.running += 1;
-                    }
-                    "Queued" => {
-                        entry.queued += 1;
-                    }
-                    "Paused" => {
-                        entry.paused += 1;
-                    }
-                    "Done" => {
-                        entry.done += 1;
-                        let result = detail.get("result").and_then(|v| v.as_str()).unwrap_or("Unknown");
-                        if result == "Success" {
-                            entry.success += 1;
-                        } else {
-                            entry.failed += 1;
-                            entry.failed_ids.push(id.clone());
-                        }
-                        let start = detail.get("start").and_then(|v| v.as_str());
-                        let end = detail.get("end").and_then(|v| v.as_str());
-                        if let (Some(start), Some(end)) = (start, end) {
-                            if let (Ok(start_ms), Ok(end_ms)) = (
-                                chrono::DateTime::parse_from_rfc3339(start),
-                                chrono::DateTime::parse_from_rfc3339(end),
-                            ) {
-                                let duration = (end_ms.timestamp_millis() - start_ms.timestamp_millis()) as f64;
-                                entry.durations.push(duration);
-                            }
-                        }
-                    }
-                    _ => {}
+        let entry = stats.entry(task.group.clone()).or_default();
+        entry.total += 1;
+        match &task.status {
+            TaskStatus::Running { .. } => entry.running += 1,
+            TaskStatus::Queued { .. } => entry.queued += 1,
+            TaskStatus::Paused { .. } => entry.paused += 1,
+            TaskStatus::Stashed { .. } => entry.stashed += 1,
+            TaskStatus::Locked { .. } => entry.locked += 1,
+            TaskStatus::Done {
+                start, end, result, ..
+            } => {
+                entry.done += 1;
+                *entry.results.entry(result.to_string()).or_default() += 1;
+                if matches!(result, TaskResult::Success) {
+                    entry.success += 1;
+                } else {
+                    entry.failed += 1;
+                    entry.failed_ids.push(*id);
                 }
+                entry
+                    .durations
+                    .push((*end - *start).num_milliseconds() as f64);
             }
         }
     }
@@ -436,12 +1647,9 @@ fn compute_group_stats(status: &serde_json::Value) -> (serde_json::Value, String
         } else {
             None
         };
-        let parallel = status
-            .get("groups")
-            .and_then(|v| v.as_object())
-            .and_then(|g| g.get(&group))
-            .and_then(|v| v.get("parallel_tasks"))
-            .and_then(|v| v.as_u64());
+        let mut sorted = nums.clone();
+        sorted.sort_by(f64::total_cmp);
+        let parallel = state.groups.get(&group).map(|group| group.parallel_tasks);
         final_stats.insert(
             group,
             json!({
@@ -449,34 +1657,33 @@ fn compute_group_stats(status: &serde_json::Value) -> (serde_json::Value, String
                 "running": entry.running,
                 "queued": entry.queued,
                 "paused": entry.paused,
+                "stashed": entry.stashed,
+                "locked": entry.locked,
                 "done": entry.done,
                 "success": entry.success,
                 "failed": entry.failed,
+                "results": entry.results,
                 "failed_ids": entry.failed_ids,
                 "avg_ms": avg,
                 "stddev_ms": stddev,
+                "p50_ms": analytics::percentile(&sorted, 50.0),
+                "p90_ms": analytics::percentile(&sorted, 90.0),
+                "p99_ms": analytics::percentile(&sorted, 99.0),
                 "parallel": parallel,
             }),
         );
     }
 
-    if let Some(groups) = status.get("groups").and_then(|v| v.as_object()) {
-        let mut group_keys: Vec<&String> = groups.keys().collect();
-        group_keys.sort();
-        for name in group_keys {
-            if let Some(group) = groups.get(name).and_then(|v| v.as_object()) {
-                hash_str(&mut hash, name);
-                if let Some(parallel) = group.get("parallel_tasks") {
-                    hash_str(&mut hash, &parallel.to_string());
-                }
-                if let Some(state) = group.get("status").and_then(|v| v.as_str()) {
-                    hash_str(&mut hash, state);
-                }
-            }
-        }
+    for (name, group) in &state.groups {
+        hash_str(&mut hash, name);
+        hash_str(&mut hash, &group.parallel_tasks.to_string());
+        hash_str(&mut hash, &format!("{:?}", group.status));
     }
 
-    (json!({ "groups": final_stats }), format!("{}:{}", hash, task_count))
+    (
+        json!({ "groups": final_stats }),
+        format!("{}:{}", hash, state.tasks.len()),
+    )
 }
 
 fn hash_str(hash: &mut u64, value: &str) {
@@ -493,7 +1700,8 @@ struct StatusCache {
 #[derive(Clone)]
 struct StatusCacheEntry {
     at: Instant,
-    payload: serde_json::Value,
+    payload: State,
     stats: serde_json::Value,
     digest: String,
 }
+
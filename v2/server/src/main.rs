@@ -2,12 +2,20 @@ use std::fs::File;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use daemonize::Daemonize;
 use env_logger::Env;
+use log::{info, warn};
+use pueue_lib::settings::Shared;
 
-use pueue_webui_v2_server::create_app;
+use pueue_webui_v2_server::archive::Archive;
+use pueue_webui_v2_server::audit::AuditLog;
+use pueue_webui_v2_server::auth::{AuthConfig, AuthMiddleware};
 use pueue_webui_v2_server::pueue_backend::RealBackend;
+use pueue_webui_v2_server::roles::RolePolicy;
+use pueue_webui_v2_server::templates::TemplateStore;
+use pueue_webui_v2_server::tls::{self, TlsOptions};
+use pueue_webui_v2_server::{create_multi_app, AppOptions, Daemon};
 
 fn main() -> Result<()> {
     let args = Args::from_env();
@@ -27,24 +35,139 @@ fn main() -> Result<()> {
 
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let backend = Arc::new(RealBackend::new()?);
-    let app = create_app(backend);
+    let auth = AuthMiddleware::from_config(&args.auth.clone().with_env_defaults())?;
+    let roles = args
+        .roles_file
+        .clone()
+        .or_else(|| std::env::var("PUEUE_WEBUI_ROLES_FILE").ok().map(PathBuf::from))
+        .map(|path| RolePolicy::from_file(&path))
+        .transpose()?;
+    if auth.is_none() {
+        warn!("No authentication configured, everybody who can reach the server has full access");
+        if roles.is_some() {
+            warn!("Roles only apply to authenticated users and are ignored without authentication");
+        }
+    }
+    let audit = AuditLog::from_env(args.audit_log.clone()).map(Arc::new);
+    if let Some(audit) = audit.as_ref() {
+        info!("Writing audit log to {}", audit.path().display());
+    }
+    let archive = Archive::from_env(args.archive.clone())?.map(Arc::new);
+    let templates = TemplateStore::from_env(args.templates.clone())?.map(Arc::new);
+    if let Some(templates) = templates.as_ref() {
+        info!("Storing task templates in {}", templates.path().display());
+    }
+    let options = AppOptions {
+        auth,
+        roles,
+        audit,
+        archive,
+        templates,
+    };
+
+    let mut daemons = configured_daemons(&args)?.into_iter();
+    let (primary, shared) = match daemons.next() {
+        Some(primary) => primary,
+        None => real_daemon("default".to_string(), RealBackend::new()?),
+    };
+    let app = create_multi_app(
+        primary,
+        daemons.map(|(daemon, _)| daemon).collect(),
+        options,
+    );
+    let tls = args.tls.clone().with_env_defaults().paths(&shared)?;
 
     let host = args
         .host
         .or_else(|| std::env::var("PUEUE_WEBUI_HOST").ok())
         .unwrap_or_else(|| "127.0.0.1:9093".to_string());
-    async_std::task::block_on(async {
-        app.listen(host).await
-    })?;
+    match tls {
+        Some((cert, key)) => {
+            let config = tls::server_config(&cert, &key)?;
+            async_std::task::block_on(tls::listen(app, &host, config))?;
+        }
+        None => {
+            async_std::task::block_on(async { app.listen(host).await })?;
+        }
+    }
     Ok(())
 }
 
+/// Wrap a backend, keeping its shared settings around for `--tls-from-daemon`.
+fn real_daemon(name: String, backend: RealBackend) -> (Daemon, Shared) {
+    let shared = backend.settings().shared.clone();
+    (Daemon::new(name, Arc::new(backend)), shared)
+}
+
+/// Collect the daemons passed via `--daemon name=path` / `PUEUE_WEBUI_DAEMONS` and
+/// `--profile name` / `PUEUE_WEBUI_PROFILES`. Empty if only the default daemon is used.
+fn configured_daemons(args: &Args) -> Result<Vec<(Daemon, Shared)>> {
+    let mut daemons = Vec::new();
+
+    let mut paths = args.daemons.clone();
+    paths.extend(env_list("PUEUE_WEBUI_DAEMONS"));
+    for entry in paths {
+        let Some((name, path)) = entry.split_once('=') else {
+            bail!("Invalid daemon '{entry}', expected name=/path/to/pueue.yml");
+        };
+        let path = PathBuf::from(path);
+        let backend = RealBackend::from_config(Some(path.clone()), None)?;
+        let (mut daemon, shared) = real_daemon(validate_daemon_name(name)?, backend);
+        daemon.config_path = Some(path);
+        daemons.push((daemon, shared));
+    }
+
+    let mut profiles = args.profiles.clone();
+    profiles.extend(env_list("PUEUE_WEBUI_PROFILES"));
+    for profile in profiles {
+        let name = validate_daemon_name(&profile)?;
+        // Profiles are sections of the main config, which is where their callback lives too.
+        let config_path = std::env::var("PUEUE_CONFIG").ok().map(PathBuf::from);
+        let backend = RealBackend::from_config(config_path, Some(&profile))?;
+        let (mut daemon, shared) = real_daemon(name, backend);
+        daemon.profile = Some(profile);
+        daemons.push((daemon, shared));
+    }
+
+    Ok(daemons)
+}
+
+fn validate_daemon_name(name: &str) -> Result<String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!("Invalid daemon name '{name}', only [A-Za-z0-9_-] are allowed");
+    }
+    Ok(name.to_string())
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    std::env::var(key)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 #[derive(Default)]
 struct Args {
     daemonize: bool,
     host: Option<String>,
     pid_file: Option<PathBuf>,
+    daemons: Vec<String>,
+    profiles: Vec<String>,
+    auth: AuthConfig,
+    roles_file: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    archive: Option<PathBuf>,
+    templates: Option<PathBuf>,
+    tls: TlsOptions,
 }
 
 impl Args {
@@ -64,6 +187,67 @@ impl Args {
                         args.pid_file = Some(PathBuf::from(value));
                     }
                 }
+                "--daemon" => {
+                    if let Some(value) = iter.next() {
+                        args.daemons.push(value);
+                    }
+                }
+                "--profile" => {
+                    if let Some(value) = iter.next() {
+                        args.profiles.push(value);
+                    }
+                }
+                "--auth-token-file" => {
+                    if let Some(value) = iter.next() {
+                        args.auth.token_file = Some(PathBuf::from(value));
+                    }
+                }
+                "--auth-htpasswd" => {
+                    if let Some(value) = iter.next() {
+                        args.auth.htpasswd_file = Some(PathBuf::from(value));
+                    }
+                }
+                "--auth-proxy-header" => {
+                    if let Some(value) = iter.next() {
+                        args.auth.proxy_header = Some(value);
+                    }
+                }
+                "--tls-cert" => {
+                    if let Some(value) = iter.next() {
+                        args.tls.cert = Some(PathBuf::from(value));
+                    }
+                }
+                "--tls-key" => {
+                    if let Some(value) = iter.next() {
+                        args.tls.key = Some(PathBuf::from(value));
+                    }
+                }
+                "--tls-from-daemon" => args.tls.from_daemon = true,
+                "--roles" => {
+                    if let Some(value) = iter.next() {
+                        args.roles_file = Some(PathBuf::from(value));
+                    }
+                }
+                "--audit-log" => {
+                    if let Some(value) = iter.next() {
+                        args.audit_log = Some(PathBuf::from(value));
+                    }
+                }
+                "--archive" => {
+                    if let Some(value) = iter.next() {
+                        args.archive = Some(PathBuf::from(value));
+                    }
+                }
+                "--templates" => {
+                    if let Some(value) = iter.next() {
+                        args.templates = Some(PathBuf::from(value));
+                    }
+                }
+                "--auth-trusted-proxy" => {
+                    if let Some(value) = iter.next() {
+                        args.auth.trusted_proxies.push(value);
+                    }
+                }
                 _ => {}
             }
         }
@@ -0,0 +1,376 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use pueue_lib::task::TaskStatus;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::{cached_status, AppState};
+
+/// Buckets of the request latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets of the task duration histogram, in seconds.
+const DURATION_BUCKETS: [f64; 12] = [
+    1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 21600.0, 86400.0,
+];
+
+/// Fallbacks to the pueue CLI, by the operation that needed it. Global, as the
+/// backends don't know about the app they're used by.
+static CLI_FALLBACKS: OnceLock<Mutex<BTreeMap<String, u64>>> = OnceLock::new();
+
+/// Count a fallback to the pueue CLI.
+pub(crate) fn record_cli_fallback(context: &str) {
+    let counts = CLI_FALLBACKS.get_or_init(Default::default);
+    let mut counts = counts.lock().unwrap_or_else(|err| err.into_inner());
+    *counts.entry(context.to_string()).or_default() += 1;
+}
+
+#[derive(Clone, Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &[(&str, &str)]) {
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let le = bound.to_string();
+            let mut labels = labels.to_vec();
+            labels.push(("le", &le));
+            sample(out, &format!("{name}_bucket"), &labels, *count as f64);
+        }
+        let mut all = labels.to_vec();
+        all.push(("le", "+Inf"));
+        sample(out, &format!("{name}_bucket"), &all, self.count as f64);
+        sample(out, &format!("{name}_sum"), labels, self.sum);
+        sample(out, &format!("{name}_count"), labels, self.count as f64);
+    }
+}
+
+/// Request metrics of the web server itself, exported on `/metrics` together
+/// with the state of all daemons.
+#[derive(Default)]
+pub struct Metrics {
+    /// Latencies by method and route.
+    latencies: Mutex<BTreeMap<(String, String), Histogram>>,
+    /// Requests by method, route and status.
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+}
+
+impl Metrics {
+    fn observe_request(&self, method: &str, route: String, status: u16, seconds: f64) {
+        let key = (method.to_string(), route);
+        self.latencies
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(key.clone())
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(seconds);
+        *self
+            .requests
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry((key.0, key.1, status))
+            .or_default() += 1;
+    }
+
+    /// Render everything in the Prometheus text format.
+    pub async fn render(&self, daemons: &[(String, AppState)]) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "pueue_daemon_up",
+            "gauge",
+            "Whether the daemon answered the last status request.",
+        );
+        let mut states = Vec::new();
+        for (name, state) in daemons {
+            let entry = cached_status(state).await.ok();
+            let up = if entry.is_some() { 1.0 } else { 0.0 };
+            sample(&mut out, "pueue_daemon_up", &[("daemon", name)], up);
+            if let Some((entry, _)) = entry {
+                states.push((name, entry));
+            }
+        }
+
+        header(
+            &mut out,
+            "pueue_tasks",
+            "gauge",
+            "Tasks by group and status.",
+        );
+        for (daemon, entry) in &states {
+            for (group, stats) in groups(&entry.stats) {
+                for status in [
+                    "running", "queued", "paused", "stashed", "locked", "success", "failed",
+                ] {
+                    let value = stats[status].as_f64().unwrap_or(0.0);
+                    sample(
+                        &mut out,
+                        "pueue_tasks",
+                        &[("daemon", daemon), ("group", group), ("status", status)],
+                        value,
+                    );
+                }
+            }
+        }
+
+        let gauges = [
+            (
+                "pueue_group_tasks_total",
+                "total",
+                1.0,
+                "All tasks of the group.",
+            ),
+            (
+                "pueue_group_parallel_tasks",
+                "parallel",
+                1.0,
+                "How many tasks of the group may run at once.",
+            ),
+            (
+                "pueue_task_duration_avg_seconds",
+                "avg_ms",
+                1000.0,
+                "Average duration of finished tasks.",
+            ),
+            (
+                "pueue_task_duration_stddev_seconds",
+                "stddev_ms",
+                1000.0,
+                "Standard deviation of the duration of finished tasks.",
+            ),
+        ];
+        for (name, key, divisor, help) in gauges {
+            header(&mut out, name, "gauge", help);
+            for (daemon, entry) in &states {
+                for (group, stats) in groups(&entry.stats) {
+                    if let Some(value) = stats[key].as_f64() {
+                        sample(
+                            &mut out,
+                            name,
+                            &[("daemon", daemon), ("group", group)],
+                            value / divisor,
+                        );
+                    }
+                }
+            }
+        }
+
+        header(
+            &mut out,
+            "pueue_task_duration_seconds",
+            "histogram",
+            "Durations of the finished tasks that are still in the daemon's state.",
+        );
+        for (daemon, entry) in &states {
+            let mut histograms: BTreeMap<&str, Histogram> = entry
+                .payload
+                .groups
+                .keys()
+                .map(|group| (group.as_str(), Histogram::new(&DURATION_BUCKETS)))
+                .collect();
+            for task in entry.payload.tasks.values() {
+                if let TaskStatus::Done { start, end, .. } = &task.status {
+                    let seconds = (*end - *start).num_milliseconds() as f64 / 1000.0;
+                    histograms
+                        .entry(task.group.as_str())
+                        .or_insert_with(|| Histogram::new(&DURATION_BUCKETS))
+                        .observe(seconds);
+                }
+            }
+            for (group, histogram) in histograms {
+                histogram.render(
+                    &mut out,
+                    "pueue_task_duration_seconds",
+                    &[("daemon", daemon), ("group", group)],
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "pueue_cli_fallback_total",
+            "counter",
+            "Requests that fell back to the pueue CLI.",
+        );
+        if let Some(counts) = CLI_FALLBACKS.get() {
+            let counts = counts.lock().unwrap_or_else(|err| err.into_inner()).clone();
+            for (context, count) in counts {
+                sample(
+                    &mut out,
+                    "pueue_cli_fallback_total",
+                    &[("operation", &context)],
+                    count as f64,
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "pueue_webui_requests_total",
+            "counter",
+            "Handled requests by route and status.",
+        );
+        let requests = self
+            .requests
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+        for ((method, route, status), count) in requests {
+            let status = status.to_string();
+            sample(
+                &mut out,
+                "pueue_webui_requests_total",
+                &[("method", &method), ("route", &route), ("status", &status)],
+                count as f64,
+            );
+        }
+
+        header(
+            &mut out,
+            "pueue_webui_request_duration_seconds",
+            "histogram",
+            "Time until the response headers were ready.",
+        );
+        let latencies = self
+            .latencies
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+        for ((method, route), histogram) in latencies {
+            histogram.render(
+                &mut out,
+                "pueue_webui_request_duration_seconds",
+                &[("method", &method), ("route", &route)],
+            );
+        }
+
+        out
+    }
+}
+
+/// The per-group entries of the output of `compute_group_stats`.
+fn groups(stats: &serde_json::Value) -> impl Iterator<Item = (&str, &serde_json::Value)> {
+    stats["groups"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(group, stats)| (group.as_str(), stats))
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape_label(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The route pattern a response came from, so that the metrics don't get a label per task id.
+#[derive(Clone, Debug)]
+struct RouteLabel(String);
+
+/// Labels the responses of a route with its pattern. Added to every route, so that
+/// responses without a label didn't come from a handler, e.g. unknown paths or 401s.
+pub(crate) struct MatchedRoute {
+    pattern: String,
+}
+
+impl MatchedRoute {
+    pub(crate) fn new(pattern: String) -> Self {
+        Self { pattern }
+    }
+}
+
+#[async_trait]
+impl Middleware<AppState> for MatchedRoute {
+    async fn handle(&self, req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+        let mut res = next.run(req).await;
+        res.insert_ext(RouteLabel(self.pattern.clone()));
+        Ok(res)
+    }
+}
+
+/// Records the latency and status of every request.
+pub(crate) struct MetricsMiddleware {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsMiddleware {
+    pub(crate) fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+#[async_trait]
+impl Middleware<AppState> for MetricsMiddleware {
+    async fn handle(&self, req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let res = next.run(req).await;
+        // Everything that didn't reach a handler ends up in one series, instead of one per path.
+        let route = res
+            .ext::<RouteLabel>()
+            .map_or_else(|| "unmatched".to_string(), |label| label.0.clone());
+        self.metrics.observe_request(
+            &method,
+            route,
+            res.status() as u16,
+            started.elapsed().as_secs_f64(),
+        );
+        Ok(res)
+    }
+}
+
+/// `GET /metrics`
+pub(crate) async fn metrics_handler(metrics: Arc<Metrics>, req: Request<AppState>) -> tide::Result {
+    let body = metrics.render(&req.state().daemons).await;
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_body(body);
+    response.set_content_type("text/plain; version=0.0.4; charset=utf-8");
+    Ok(response)
+}
@@ -1,28 +1,40 @@
 use std::collections::BTreeMap;
-use std::process::Command;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use log::warn;
 use serde_json::json;
 
+use async_std::channel::Sender;
 use pueue_lib::message::{
-    AddRequest, GroupRequest, KillRequest, LogRequest, PauseRequest, Request, Response,
-    RestartRequest, StartRequest, TaskSelection, TaskToRestart,
+    AddRequest, CleanRequest, EditableTask, EnqueueRequest, EnvRequest, GroupRequest, KillRequest, LogRequest,
+    ParallelRequest, PauseRequest, Request, ResetRequest, ResetTarget, Response, RestartRequest,
+    SendRequest, Signal, StartRequest, StashRequest, StreamRequest, SwitchRequest, TaskSelection,
+    TaskToRestart,
 };
-use pueue_lib::network_blocking::socket::ConnectionSettings;
 use pueue_lib::network_blocking::BlockingClient;
-use pueue_lib::secret::read_shared_secret;
 use pueue_lib::settings::Settings;
-use pueue_lib::state::{Group, State};
+use pueue_lib::state::State;
+use pueue_lib::task::TaskStatus;
 
-use crate::{AddTaskRequest, GroupActionRequest, PueueBackend};
+use crate::connection_pool::{connect_stream, ConnectionPool};
+use crate::error::ApiError;
+use crate::metrics::record_cli_fallback;
+use crate::{
+    parse_enqueue_at, AddTaskRequest, BulkSelection, GroupActionRequest, LogStream, LogStreamEvent,
+    PueueBackend, TaskActionRequest,
+};
 
 static CLI_FALLBACK_USED: AtomicBool = AtomicBool::new(false);
 
 pub struct RealBackend {
     settings: Settings,
+    pool: ConnectionPool,
 }
 
 impl RealBackend {
@@ -30,20 +42,31 @@ impl RealBackend {
         let config_path = std::env::var("PUEUE_CONFIG")
             .ok()
             .map(std::path::PathBuf::from);
-        let require_config = std::env::var("PUEUE_REQUIRE_CONFIG")
-            .ok()
-            .map(|value| value != "0")
-            .unwrap_or(true);
-
-        let (mut settings, found) = Settings::read(&config_path)
-            .map_err(|err| anyhow!(err.to_string()))?;
+        let mut settings = read_settings(&config_path)?;
+        apply_path_overrides(&mut settings);
+        Ok(Self::with_settings(settings))
+    }
 
-        if require_config && !found {
-            bail!("Couldn't find a configuration file. Did you start the daemon yet?");
+    /// Connect to the daemon of a specific config file and, optionally, one of its profiles.
+    /// Unlike [`RealBackend::new`], the `PUEUE_*` path overrides aren't applied.
+    pub fn from_config(
+        config_path: Option<std::path::PathBuf>,
+        profile: Option<&str>,
+    ) -> Result<Self> {
+        let mut settings = read_settings(&config_path)?;
+        if let Some(profile) = profile {
+            settings.load_profile(profile)?;
         }
+        Ok(Self::with_settings(settings))
+    }
 
-        apply_path_overrides(&mut settings);
-        Ok(Self { settings })
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    fn with_settings(settings: Settings) -> Self {
+        let pool = ConnectionPool::from_env(settings.clone());
+        Self { settings, pool }
     }
 
     async fn with_client<F, R>(&self, handler: F) -> Result<R>
@@ -51,40 +74,25 @@ impl RealBackend {
         F: FnOnce(&mut BlockingClient) -> Result<R> + Send + 'static,
         R: Send + 'static,
     {
-        let settings = self.settings.clone();
-        async_std::task::spawn_blocking(move || {
-            let connection_settings = ConnectionSettings::try_from(settings.shared.clone())
-                .map_err(|err| anyhow!(err.to_string()))?;
-            let secret_path = settings.shared.shared_secret_path();
-            let secret = read_shared_secret(secret_path.as_path())
-                .map_err(|err| anyhow!(err.to_string()))?;
-            let mut client = BlockingClient::new(connection_settings, &secret, true)
-                .map_err(|err| anyhow!(err.to_string()))?;
-            handler(&mut client)
-        })
-        .await
+        self.pool.run(handler).await
     }
 
     async fn get_state(&self) -> Result<State> {
         self.with_client(|client| {
             client.send_request(Request::Status)?;
-            match client.receive_response()? {
-                Response::Status(state) => Ok(*state),
-                Response::Failure(text) => bail!(text),
-                other => bail!("Unexpected response: {:?}", other),
-            }
-        })
-        .await
-    }
-
-    async fn get_groups(&self) -> Result<BTreeMap<String, Group>> {
-        self.with_client(|client| {
-            client.send_request(Request::Group(GroupRequest::List))?;
-            match client.receive_response()? {
-                Response::Group(response) => Ok(response.groups),
-                Response::Failure(text) => bail!(text),
-                other => bail!("Unexpected response: {:?}", other),
+            let mut state = match client.receive_response()? {
+                Response::Status(state) => *state,
+                Response::Failure(text) => bail!(ApiError::daemon_failure(text)),
+                other => bail!(unexpected_response(&other)),
+            };
+            // Only fall back to the group list on the same connection if the state lacks it.
+            if state.groups.is_empty() {
+                client.send_request(Request::Group(GroupRequest::List))?;
+                if let Response::Group(response) = client.receive_response()? {
+                    state.groups = response.groups;
+                }
             }
+            Ok(state)
         })
         .await
     }
@@ -94,8 +102,8 @@ impl RealBackend {
             client.send_request(message)?;
             match client.receive_response()? {
                 Response::Success(text) => Ok(text),
-                Response::Failure(text) => bail!(text),
-                other => bail!("Unexpected response: {:?}", other),
+                Response::Failure(text) => bail!(ApiError::daemon_failure(text)),
+                other => bail!(unexpected_response(&other)),
             }
         })
         .await
@@ -103,70 +111,204 @@ impl RealBackend {
 
     fn map_action_request(
         &self,
-        action: &str,
-        task_id: usize,
+        request: &TaskActionRequest,
+        tasks: TaskSelection,
         state: Option<&State>,
     ) -> Result<Request> {
+        let action = request.action.as_str();
         match action {
-            "start" | "resume" => Ok(Request::Start(StartRequest {
-                tasks: TaskSelection::TaskIds(vec![task_id]),
-            })),
-            "pause" => Ok(Request::Pause(PauseRequest {
-                tasks: TaskSelection::TaskIds(vec![task_id]),
-                wait: false,
-            })),
+            "start" | "resume" => Ok(Request::Start(StartRequest { tasks })),
+            "pause" => Ok(Request::Pause(PauseRequest { tasks, wait: false })),
             "kill" => Ok(Request::Kill(KillRequest {
-                tasks: TaskSelection::TaskIds(vec![task_id]),
-                signal: None,
+                tasks,
+                signal: request
+                    .signal
+                    .as_deref()
+                    .map(Signal::from_str)
+                    .transpose()
+                    .map_err(|_| anyhow!("Unknown signal"))?,
             })),
-            "remove" => Ok(Request::Remove(vec![task_id])),
+            "remove" => Ok(Request::Remove(selected_ids(&tasks, state)?)),
             "restart" => {
                 let state = state.context("Missing state for restart")?;
-                let task = state.tasks.get(&task_id).context("Task not found")?;
+                let tasks = selected_ids(&tasks, Some(state))?
+                    .into_iter()
+                    .map(|task_id| {
+                        let task = state.tasks.get(&task_id).context("Task not found")?;
+                        Ok(task_to_restart(task_id, task, request))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
                 Ok(Request::Restart(RestartRequest {
-                    tasks: vec![TaskToRestart {
-                        task_id,
-                        original_command: task.original_command.clone(),
-                        path: task.path.clone(),
-                        label: task.label.clone(),
-                        priority: task.priority,
-                    }],
-                    start_immediately: true,
-                    stashed: false,
+                    tasks,
+                    start_immediately: request.restart.start_immediately(),
+                    stashed: request.restart.stashed(),
                 }))
             }
+            "stash" => Ok(Request::Stash(StashRequest {
+                tasks,
+                enqueue_at: action_enqueue_at(request)?,
+            })),
+            "enqueue" => Ok(Request::Enqueue(EnqueueRequest {
+                tasks,
+                enqueue_at: action_enqueue_at(request)?,
+            })),
             _ => bail!("Unsupported action: {action}"),
         }
     }
+
+    async fn run_action(
+        &self,
+        tasks: TaskSelection,
+        request: &TaskActionRequest,
+    ) -> Result<serde_json::Value> {
+        let action = request.action.as_str();
+        let needs_state = action == "restart"
+            || (action == "remove" && !matches!(tasks, TaskSelection::TaskIds(_)));
+        let state = if needs_state {
+            Some(self.get_state().await?)
+        } else {
+            None
+        };
+
+        let in_place = request
+            .restart
+            .in_place
+            .unwrap_or(self.settings.client.restart_in_place);
+        if action == "restart" && !in_place {
+            let state = state.context("Missing state for restart")?;
+            // Checked here, as the daemon would happily add copies of unfinished tasks.
+            let adds = restart_requests(&tasks, request, &state)?;
+            return self.restart_as_new(adds, &tasks, request).await;
+        }
+
+        match self.map_action_request(request, tasks.clone(), state.as_ref()) {
+            Ok(message) => match self.send_and_expect_success(message).await {
+                Ok(result) => Ok(json!({ "message": result })),
+                Err(error) if cli_fallback_enabled() => {
+                    log_cli_fallback_once("action", &error.to_string());
+                    run_cli_action(&tasks, request)
+                }
+                Err(error) => Err(error),
+            },
+            Err(error) if cli_fallback_enabled() => {
+                log_cli_fallback_once("action", &error.to_string());
+                run_cli_action(&tasks, request)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Restart finished tasks by adding copies of them, which keeps the old tasks and logs.
+    /// This is what the pueue CLI does, unless `restart_in_place` is set.
+    async fn restart_as_new(
+        &self,
+        adds: Vec<(usize, AddRequest)>,
+        tasks: &TaskSelection,
+        request: &TaskActionRequest,
+    ) -> Result<serde_json::Value> {
+        // Collected outside of the connection, so that a connection error doesn't lose them.
+        let restarted = Arc::new(Mutex::new(Vec::new()));
+        let collected = restarted.clone();
+        let sent = self
+            .with_client(move |client| {
+                for (task_id, add) in adds {
+                    client.send_request(Request::Add(add))?;
+                    match client.receive_response()? {
+                        Response::AddedTask(task) => collected
+                            .lock()
+                            .unwrap_or_else(|err| err.into_inner())
+                            .push((task_id, task.task_id)),
+                        Response::Failure(text) => bail!(ApiError::daemon_failure(text)),
+                        other => bail!(unexpected_response(&other)),
+                    }
+                }
+                Ok(())
+            })
+            .await;
+
+        let restarted = std::mem::take(&mut *restarted.lock().unwrap_or_else(|err| err.into_inner()));
+        match sent {
+            Ok(()) => {
+                let added: Vec<usize> = restarted.iter().map(|(_, added)| *added).collect();
+                Ok(json!({ "message": "Tasks restarted", "added": added }))
+            }
+            // Only fall back if nothing has been added yet, as the CLI would restart those again.
+            Err(error) if restarted.is_empty() && cli_fallback_enabled() => {
+                log_cli_fallback_once("action", &error.to_string());
+                run_cli_action(tasks, request)
+            }
+            Err(error) if restarted.is_empty() => Err(error),
+            Err(error) => {
+                let error = ApiError::from(&error);
+                let list = |ids: Vec<String>| ids.join(", ");
+                let message = format!(
+                    "{} (tasks {} have already been restarted as {})",
+                    error.message(),
+                    list(restarted.iter().map(|(id, _)| id.to_string()).collect()),
+                    list(restarted.iter().map(|(_, added)| added.to_string()).collect()),
+                );
+                Err(error.with_message(message).into())
+            }
+        }
+    }
+}
+
+/// The tasks to add for restarting the selected ones as new tasks, paired with their ids.
+fn restart_requests(
+    tasks: &TaskSelection,
+    request: &TaskActionRequest,
+    state: &State,
+) -> Result<Vec<(usize, AddRequest)>> {
+    selected_ids(tasks, Some(state))?
+        .into_iter()
+        .map(|task_id| {
+            let task = state.tasks.get(&task_id).context("Task not found")?;
+            if !matches!(task.status, TaskStatus::Done { .. }) {
+                bail!(ApiError::Conflict(format!("Task {task_id} hasn't finished yet")));
+            }
+            let restarted = task_to_restart(task_id, task, request);
+            let add = AddRequest {
+                command: restarted.original_command,
+                path: restarted.path,
+                envs: task.envs.clone(),
+                start_immediately: request.restart.start_immediately(),
+                stashed: request.restart.stashed(),
+                group: task.group.clone(),
+                enqueue_at: None,
+                dependencies: Vec::new(),
+                priority: Some(restarted.priority),
+                label: restarted.label,
+            };
+            Ok((task_id, add))
+        })
+        .collect()
+}
+
+fn task_to_restart(
+    task_id: usize,
+    task: &pueue_lib::task::Task,
+    request: &TaskActionRequest,
+) -> TaskToRestart {
+    let mut restarted = TaskToRestart {
+        task_id,
+        original_command: task.original_command.clone(),
+        path: task.path.clone(),
+        label: task.label.clone(),
+        priority: task.priority,
+    };
+    request.restart.apply(&mut restarted);
+    restarted
 }
 
 #[async_trait]
 impl PueueBackend for RealBackend {
-    async fn status(&self) -> Result<serde_json::Value> {
+    async fn status(&self) -> Result<State> {
         match self.get_state().await {
-            Ok(mut state) => {
-                match self.get_groups().await {
-                    Ok(groups) => {
-                        if !groups.is_empty() {
-                            state.groups = groups;
-                        }
-                    }
-                    Err(error) if cli_fallback_enabled() => {
-                        log_cli_fallback_once("groups", &error.to_string());
-                        if let Ok(groups) = run_cli_groups() {
-                            if !groups.is_empty() {
-                                state.groups = groups;
-                            }
-                        }
-                    }
-                    Err(_) => {}
-                }
-                Ok(serde_json::to_value(state)?)
-            }
+            Ok(state) => Ok(state),
             Err(error) if cli_fallback_enabled() => {
                 log_cli_fallback_once("status", &error.to_string());
                 let cli_status = run_cli_json(&["status", "--json"])?;
-                Ok(cli_status)
+                Ok(serde_json::from_value(cli_status)?)
             }
             Err(error) => Err(error),
         }
@@ -182,8 +324,8 @@ impl PueueBackend for RealBackend {
                 }))?;
                 match client.receive_response()? {
                     Response::Log(map) => Ok(log_map_to_json(map, task_id)),
-                    Response::Failure(text) => bail!(text),
-                    other => bail!("Unexpected response: {:?}", other),
+                    Response::Failure(text) => bail!(ApiError::daemon_failure(text)),
+                    other => bail!(unexpected_response(&other)),
                 }
             })
             .await;
@@ -198,66 +340,29 @@ impl PueueBackend for RealBackend {
         }
     }
 
-    async fn action(&self, task_id: usize, action: &str) -> Result<serde_json::Value> {
-        let state = if action == "restart" {
-            Some(self.get_state().await?)
-        } else {
-            None
-        };
+    async fn action(
+        &self,
+        task_id: usize,
+        request: &TaskActionRequest,
+    ) -> Result<serde_json::Value> {
+        self.run_action(TaskSelection::TaskIds(vec![task_id]), request)
+            .await
+    }
 
-        match self.map_action_request(action, task_id, state.as_ref()) {
-            Ok(message) => match self.send_and_expect_success(message).await {
-                Ok(result) => Ok(json!({ "message": result })),
-                Err(error) if cli_fallback_enabled() => {
-                    log_cli_fallback_once("action", &error.to_string());
-                    run_cli_action(task_id, action)
-                }
-                Err(error) => Err(error),
-            },
-            Err(error) if cli_fallback_enabled() => {
-                log_cli_fallback_once("action", &error.to_string());
-                run_cli_action(task_id, action)
-            }
-            Err(error) => Err(error),
-        }
+    async fn bulk_action(
+        &self,
+        selection: BulkSelection,
+        request: &TaskActionRequest,
+    ) -> Result<serde_json::Value> {
+        self.run_action(selection.into(), request).await
     }
 
     async fn add_task(&self, request: AddTaskRequest) -> Result<serde_json::Value> {
         let request_clone = request.clone();
-        let command = request.command.clone();
-        let group = request.group.clone().unwrap_or_else(|| "default".to_string());
-        let stashed = request.stashed.unwrap_or(false);
-        let start_immediately = request.start_immediately.unwrap_or(!stashed);
-        let path = request
-            .path
-            .clone()
-            .map(std::path::PathBuf::from)
-            .or_else(|| std::env::var("PUEUE_DEFAULT_TASK_PATH").ok().map(std::path::PathBuf::from))
-            .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| ".".into()));
-
-        let add = AddRequest {
-            command,
-            path,
-            envs: std::collections::HashMap::new(),
-            start_immediately,
-            stashed,
-            group,
-            enqueue_at: None,
-            dependencies: Vec::new(),
-            priority: request.priority,
-            label: request.label.clone(),
-        };
+        let add = add_request(&request)?;
 
         let response = self
-            .with_client(move |client| {
-                client.send_request(Request::Add(add))?;
-                match client.receive_response()? {
-                    Response::AddedTask(added) => Ok(serde_json::to_value(added)?),
-                    Response::Success(text) => Ok(json!({ "message": text })),
-                    Response::Failure(text) => bail!(text),
-                    other => bail!("Unexpected response: {:?}", other),
-                }
-            })
+            .with_client(move |client| send_add(client, add))
             .await;
 
         match response {
@@ -270,6 +375,59 @@ impl PueueBackend for RealBackend {
         }
     }
 
+    async fn add_tasks(
+        &self,
+        requests: Vec<AddTaskRequest>,
+        stop_on_failure: bool,
+    ) -> Vec<Result<serde_json::Value>> {
+        let adds: Vec<Result<AddRequest>> = requests.iter().map(add_request).collect();
+        // Collected outside of the connection, so that the results of the tasks
+        // added before a connection error aren't lost with it.
+        let results = Arc::new(Mutex::new(Vec::with_capacity(adds.len())));
+        let collected = results.clone();
+        let sent = self
+            .with_client(move |client| {
+                for add in adds {
+                    let result = match add {
+                        Ok(add) => send_add(client, add),
+                        Err(error) => Err(error),
+                    };
+                    // Protocol and IO errors leave the connection unusable, so give up on it.
+                    if let Err(error) = &result {
+                        if error.downcast_ref::<pueue_lib::Error>().is_some() {
+                            return result.map(|_| ());
+                        }
+                    }
+                    let failed = result.is_err();
+                    collected.lock().unwrap_or_else(|err| err.into_inner()).push(result);
+                    if failed && stop_on_failure {
+                        break;
+                    }
+                }
+                Ok(())
+            })
+            .await;
+
+        let mut results = std::mem::take(&mut *results.lock().unwrap_or_else(|err| err.into_inner()));
+        match sent {
+            Ok(()) => {}
+            // Only fall back if nothing has been added yet, as the CLI would add those again.
+            Err(error) if results.is_empty() && cli_fallback_enabled() => {
+                log_cli_fallback_once("add", &error.to_string());
+                for request in requests {
+                    let result = run_cli_add_task(request);
+                    let failed = result.is_err();
+                    results.push(result);
+                    if failed && stop_on_failure {
+                        break;
+                    }
+                }
+            }
+            Err(error) => results.push(Err(error)),
+        }
+        results
+    }
+
     async fn group_action(&self, request: GroupActionRequest) -> Result<serde_json::Value> {
         let name = request.name.trim().to_string();
         if name.is_empty() {
@@ -286,6 +444,22 @@ impl PueueBackend for RealBackend {
             }),
             "remove" => Request::Group(GroupRequest::Remove(name)),
             "list" => Request::Group(GroupRequest::List),
+            "parallel" => Request::Parallel(ParallelRequest {
+                parallel_tasks: request
+                    .parallel_tasks
+                    .context("Missing number of parallel tasks")?,
+                group: name,
+            }),
+            "pause" => Request::Pause(PauseRequest {
+                tasks: TaskSelection::Group(name),
+                wait: request.wait.unwrap_or(false),
+            }),
+            "start" | "resume" => Request::Start(StartRequest {
+                tasks: TaskSelection::Group(name),
+            }),
+            "reset" => Request::Reset(ResetRequest {
+                target: ResetTarget::Groups(vec![name]),
+            }),
             _ => bail!("Unsupported group action"),
         };
 
@@ -298,6 +472,223 @@ impl PueueBackend for RealBackend {
             Err(error) => Err(error),
         }
     }
+
+    async fn stream_logs(&self, task_id: usize, lines: Option<usize>) -> Result<LogStream> {
+        // Open the stream up front, so failures (e.g. unknown task) surface as a plain error
+        // instead of an already started event stream. The client isn't `Send`, so the whole
+        // stream lives on a single blocking thread.
+        let settings = self.settings.clone();
+        let (opened_sender, opened_receiver) = async_std::channel::bounded(1);
+        let (sender, receiver) = async_std::channel::bounded(64);
+        async_std::task::spawn_blocking(move || {
+            let opened = open_stream(&settings, task_id, lines, &sender);
+            match opened {
+                Ok((client, first)) => {
+                    let _ = opened_sender.send_blocking(Ok(()));
+                    forward_stream(client, first, sender);
+                }
+                Err(error) => {
+                    let _ = opened_sender.send_blocking(Err(error));
+                }
+            }
+        });
+        let opened = opened_receiver
+            .recv()
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Log stream closed unexpectedly")));
+
+        match opened {
+            Ok(()) => Ok(receiver),
+            Err(error) if cli_fallback_enabled() => {
+                log_cli_fallback_once("stream", &error.to_string());
+                run_cli_follow(task_id, lines)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn edit_request(&self, task_id: usize) -> Result<EditableTask> {
+        self.with_client(move |client| {
+            client.send_request(Request::EditRequest(vec![task_id]))?;
+            match client.receive_response()? {
+                Response::Edit(tasks) => tasks
+                    .into_iter()
+                    .next()
+                    .context("Daemon didn't return the task to edit"),
+                Response::Failure(text) => bail!(ApiError::daemon_failure(text)),
+                other => bail!(unexpected_response(&other)),
+            }
+        })
+        .await
+    }
+
+    async fn edit_submit(&self, task: EditableTask) -> Result<serde_json::Value> {
+        let result = self
+            .send_and_expect_success(Request::EditedTasks(vec![task]))
+            .await?;
+        Ok(json!({ "message": result }))
+    }
+
+    async fn edit_restore(&self, task_id: usize) -> Result<serde_json::Value> {
+        let result = self
+            .send_and_expect_success(Request::EditRestore(vec![task_id]))
+            .await?;
+        Ok(json!({ "message": result }))
+    }
+
+    async fn send_input(&self, task_id: usize, input: String) -> Result<serde_json::Value> {
+        let message = Request::Send(SendRequest {
+            task_id,
+            input: input.clone(),
+        });
+        match self.send_and_expect_success(message).await {
+            Ok(result) => Ok(json!({ "message": result })),
+            Err(error) if cli_fallback_enabled() => {
+                log_cli_fallback_once("send", &error.to_string());
+                let id = task_id.to_string();
+                let stdout = run_cli(&["send", &id, &input])?;
+                Ok(json!({ "message": stdout }))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn set_env(
+        &self,
+        task_id: usize,
+        key: String,
+        value: String,
+    ) -> Result<serde_json::Value> {
+        let message = Request::Env(EnvRequest::Set {
+            task_id,
+            key: key.clone(),
+            value: value.clone(),
+        });
+        match self.send_and_expect_success(message).await {
+            Ok(result) => Ok(json!({ "message": result })),
+            Err(error) if cli_fallback_enabled() => {
+                log_cli_fallback_once("env", &error.to_string());
+                let id = task_id.to_string();
+                let stdout = run_cli(&["env", "set", &id, &key, &value])?;
+                Ok(json!({ "message": stdout }))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn clean(
+        &self,
+        successful_only: bool,
+        group: Option<String>,
+    ) -> Result<serde_json::Value> {
+        let message = Request::Clean(CleanRequest {
+            successful_only,
+            group: group.clone(),
+        });
+        match self.send_and_expect_success(message).await {
+            Ok(result) => Ok(json!({ "message": result })),
+            Err(error) if cli_fallback_enabled() => {
+                log_cli_fallback_once("clean", &error.to_string());
+                let mut args = vec!["clean"];
+                if successful_only {
+                    args.push("--successful-only");
+                }
+                if let Some(group) = group.as_deref() {
+                    args.push("--group");
+                    args.push(group);
+                }
+                let stdout = run_cli(&args)?;
+                Ok(json!({ "message": stdout }))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn switch_tasks(&self, first: usize, second: usize) -> Result<serde_json::Value> {
+        let message = Request::Switch(SwitchRequest {
+            task_id_1: first,
+            task_id_2: second,
+        });
+        match self.send_and_expect_success(message).await {
+            Ok(result) => Ok(json!({ "message": result })),
+            Err(error) if cli_fallback_enabled() => {
+                log_cli_fallback_once("switch", &error.to_string());
+                let stdout = run_cli(&["switch", &first.to_string(), &second.to_string()])?;
+                Ok(json!({ "message": stdout }))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn unset_env(&self, task_id: usize, key: String) -> Result<serde_json::Value> {
+        let message = Request::Env(EnvRequest::Unset {
+            task_id,
+            key: key.clone(),
+        });
+        match self.send_and_expect_success(message).await {
+            Ok(result) => Ok(json!({ "message": result })),
+            Err(error) if cli_fallback_enabled() => {
+                log_cli_fallback_once("env", &error.to_string());
+                let id = task_id.to_string();
+                let stdout = run_cli(&["env", "unset", &id, &key])?;
+                Ok(json!({ "message": stdout }))
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+fn open_stream(
+    settings: &Settings,
+    task_id: usize,
+    lines: Option<usize>,
+    sender: &Sender<LogStreamEvent>,
+) -> Result<(BlockingClient, Response)> {
+    let sender = sender.clone();
+    let mut client = connect_stream(settings, move || sender.is_closed())?;
+    client.send_request(Request::Stream(StreamRequest {
+        tasks: TaskSelection::TaskIds(vec![task_id]),
+        lines,
+    }))?;
+    match client.receive_response()? {
+        Response::Failure(text) => bail!(ApiError::daemon_failure(text)),
+        first => Ok((client, first)),
+    }
+}
+
+/// Pump `Response::Stream` chunks into the channel until the daemon closes the stream
+/// or the receiving side went away. The connection's reads notice the latter, too.
+fn forward_stream(mut client: BlockingClient, first: Response, sender: Sender<LogStreamEvent>) {
+    let mut response = first;
+    loop {
+        let event = match response {
+            Response::Stream(stream) => {
+                for output in stream.logs.into_values() {
+                    if sender.send_blocking(LogStreamEvent::Chunk(output)).is_err() {
+                        return;
+                    }
+                }
+                None
+            }
+            Response::Close => return,
+            Response::Failure(text) => Some(LogStreamEvent::Error(text)),
+            other => Some(LogStreamEvent::Error(format!(
+                "Unexpected response: {other:?}"
+            ))),
+        };
+        if let Some(event) = event {
+            let _ = sender.send_blocking(event);
+            return;
+        }
+
+        response = match client.receive_response() {
+            Ok(response) => response,
+            Err(error) => {
+                let _ = sender.send_blocking(LogStreamEvent::Error(error.to_string()));
+                return;
+            }
+        };
+    }
 }
 
 fn log_map_to_json(
@@ -318,6 +709,25 @@ fn log_map_to_json(
     }
 }
 
+/// A response that doesn't fit the request, e.g. because of a different pueue version.
+fn unexpected_response(response: &Response) -> ApiError {
+    ApiError::Protocol(format!("Unexpected response: {response:?}"))
+}
+
+fn read_settings(config_path: &Option<std::path::PathBuf>) -> Result<Settings> {
+    let require_config = std::env::var("PUEUE_REQUIRE_CONFIG")
+        .ok()
+        .map(|value| value != "0")
+        .unwrap_or(true);
+
+    let (settings, found) = Settings::read(config_path)?;
+
+    if require_config && !found {
+        bail!("Couldn't find a configuration file. Did you start the daemon yet?");
+    }
+    Ok(settings)
+}
+
 fn apply_path_overrides(settings: &mut Settings) {
     if let Ok(dir) = std::env::var("PUEUE_DIRECTORY") {
         settings.shared.pueue_directory = Some(std::path::PathBuf::from(dir));
@@ -343,7 +753,9 @@ fn cli_fallback_enabled() -> bool {
         .unwrap_or(true)
 }
 
+/// Counts every fallback for `/metrics`, but only logs the first one.
 fn log_cli_fallback_once(context: &str, error: &str) {
+    record_cli_fallback(context);
     if CLI_FALLBACK_USED
         .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
         .is_ok()
@@ -357,10 +769,18 @@ fn pueue_bin() -> String {
 }
 
 fn run_cli(args: &[&str]) -> Result<String> {
-    let output = Command::new(pueue_bin()).args(args).output()?;
+    run_cli_with_envs(args, &std::collections::HashMap::new())
+}
+
+/// Run the CLI with additional environment variables, which `pueue add` passes on to the task.
+fn run_cli_with_envs(
+    args: &[&str],
+    envs: &std::collections::HashMap<String, String>,
+) -> Result<String> {
+    let output = Command::new(pueue_bin()).args(args).envs(envs).output()?;
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        bail!(stderr.trim().to_string());
+        bail!(ApiError::cli_failure(stderr.trim()));
     }
     Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
@@ -382,16 +802,128 @@ fn run_cli_log(task_id: usize, lines: Option<usize>) -> Result<serde_json::Value
     run_cli_json(&refs)
 }
 
-fn run_cli_action(task_id: usize, action: &str) -> Result<serde_json::Value> {
+fn run_cli_follow(task_id: usize, lines: Option<usize>) -> Result<LogStream> {
+    let mut command = Command::new(pueue_bin());
+    command.arg("follow");
+    if let Some(lines) = lines {
+        command.arg("--lines").arg(lines.to_string());
+    }
+    let mut child = command
+        .arg(task_id.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().context("Missing stdout of pueue follow")?;
+
+    let (sender, receiver) = async_std::channel::bounded(64);
+    async_std::task::spawn_blocking(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match stdout.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => {
+                    let chunk = String::from_utf8_lossy(&buffer[..read]).to_string();
+                    if sender.send_blocking(LogStreamEvent::Chunk(chunk)).is_err() {
+                        let _ = child.kill();
+                        break;
+                    }
+                }
+                Err(error) => {
+                    let _ = sender.send_blocking(LogStreamEvent::Error(error.to_string()));
+                    break;
+                }
+            }
+        }
+        if let Ok(output) = child.wait_with_output() {
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                let _ = sender.send_blocking(LogStreamEvent::Error(stderr));
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// The point in time a stash or enqueue action should enqueue its tasks at.
+fn action_enqueue_at(request: &TaskActionRequest) -> Result<Option<chrono::DateTime<chrono::Local>>> {
+    request
+        .enqueue_at
+        .as_deref()
+        .map(parse_enqueue_at)
+        .transpose()
+        .map_err(|err| anyhow!(err))
+}
+
+/// The CLI interprets plain numbers as a delay in seconds.
+fn cli_delay_args(at: chrono::DateTime<chrono::Local>) -> [String; 2] {
+    let delay = (at - chrono::Local::now()).num_seconds().max(0);
+    ["--delay".to_string(), delay.to_string()]
+}
+
+fn run_cli_action(tasks: &TaskSelection, request: &TaskActionRequest) -> Result<serde_json::Value> {
+    let action = request.action.as_str();
     let command = match action {
         "resume" => "start",
         other => other,
     };
-    let id = task_id.to_string();
-    let stdout = run_cli(&[command, &id])?;
+    let mut args = vec![command.to_string()];
+    let supports_selection = matches!(command, "start" | "pause" | "kill" | "stash" | "enqueue");
+    match tasks {
+        TaskSelection::TaskIds(ids) => args.extend(ids.iter().map(|id| id.to_string())),
+        TaskSelection::Group(group) if supports_selection => {
+            args.push("--group".to_string());
+            args.push(group.clone());
+        }
+        TaskSelection::All if supports_selection => {
+            args.push("--all".to_string());
+        }
+        _ => bail!("The CLI doesn't support '{action}' for {tasks:?}"),
+    }
+    if let Some(at) = action_enqueue_at(request)? {
+        args.extend(cli_delay_args(at));
+    }
+    if let Some(signal) = request.signal.as_ref() {
+        args.push("--signal".to_string());
+        args.push(signal.clone());
+    }
+    if command == "restart" {
+        // Changing a task on restart needs an editor with the CLI.
+        if request.restart.changes_task() {
+            bail!("The CLI can't change tasks on restart");
+        }
+        match request.restart.in_place {
+            Some(true) => args.push("--in-place".to_string()),
+            Some(false) => args.push("--not-in-place".to_string()),
+            None => {}
+        }
+        if request.restart.stashed() {
+            args.push("--stashed".to_string());
+        } else if request.restart.start_immediately() {
+            args.push("--start-immediately".to_string());
+        }
+    }
+    let refs: Vec<&str> = args.iter().map(|value| value.as_str()).collect();
+    let stdout = run_cli(&refs)?;
     Ok(json!({ "message": stdout }))
 }
 
+/// Resolve a selection to task ids. Group and "all" selections require the state.
+fn selected_ids(selection: &TaskSelection, state: Option<&State>) -> Result<Vec<usize>> {
+    match selection {
+        TaskSelection::TaskIds(ids) => Ok(ids.clone()),
+        TaskSelection::Group(group) => Ok(state
+            .context("Missing state for group selection")?
+            .task_ids_in_group(group)),
+        TaskSelection::All => Ok(state
+            .context("Missing state for task selection")?
+            .tasks
+            .keys()
+            .copied()
+            .collect()),
+    }
+}
+
 fn run_cli_group(request: GroupActionRequest) -> Result<serde_json::Value> {
     let mut args = vec!["group".to_string()];
     match request.action.as_str() {
@@ -410,6 +942,35 @@ fn run_cli_group(request: GroupActionRequest) -> Result<serde_json::Value> {
         "list" => {
             args.push("list".to_string());
         }
+        "parallel" => {
+            let parallel = request
+                .parallel_tasks
+                .context("Missing number of parallel tasks")?;
+            args = vec![
+                "parallel".to_string(),
+                parallel.to_string(),
+                "--group".to_string(),
+                request.name,
+            ];
+        }
+        "pause" => {
+            args = vec!["pause".to_string(), "--group".to_string(), request.name];
+            if request.wait.unwrap_or(false) {
+                args.push("--wait".to_string());
+            }
+        }
+        "start" | "resume" => {
+            args = vec!["start".to_string(), "--group".to_string(), request.name];
+        }
+        "reset" => {
+            // Without --force, the CLI waits for a confirmation on stdin.
+            args = vec![
+                "reset".to_string(),
+                "--force".to_string(),
+                "--groups".to_string(),
+                request.name,
+            ];
+        }
         _ => bail!("Unsupported group action"),
     }
     let refs: Vec<&str> = args.iter().map(|value| value.as_str()).collect();
@@ -417,13 +978,47 @@ fn run_cli_group(request: GroupActionRequest) -> Result<serde_json::Value> {
     Ok(json!({ "message": stdout }))
 }
 
-fn run_cli_groups() -> Result<BTreeMap<String, Group>> {
-    let json = run_cli_json(&["group", "--json"])?;
-    let groups: BTreeMap<String, Group> = serde_json::from_value(json)?;
-    Ok(groups)
+fn add_request(request: &AddTaskRequest) -> Result<AddRequest> {
+    let stashed = request.stashed.unwrap_or(false);
+    let path = request
+        .path
+        .clone()
+        .map(std::path::PathBuf::from)
+        .or_else(|| std::env::var("PUEUE_DEFAULT_TASK_PATH").ok().map(std::path::PathBuf::from))
+        .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| ".".into()));
+    let enqueue_at = request
+        .enqueue_at
+        .as_deref()
+        .map(parse_enqueue_at)
+        .transpose()
+        .map_err(|err| anyhow!(err))?;
+
+    Ok(AddRequest {
+        command: request.command.clone(),
+        path,
+        envs: request.envs.clone().unwrap_or_default(),
+        start_immediately: request.start_immediately.unwrap_or(!stashed),
+        stashed,
+        group: request.group.clone().unwrap_or_else(|| "default".to_string()),
+        enqueue_at,
+        dependencies: request.dependencies.clone().unwrap_or_default(),
+        priority: request.priority,
+        label: request.label.clone(),
+    })
+}
+
+fn send_add(client: &mut BlockingClient, add: AddRequest) -> Result<serde_json::Value> {
+    client.send_request(Request::Add(add))?;
+    match client.receive_response()? {
+        Response::AddedTask(added) => Ok(serde_json::to_value(added)?),
+        Response::Success(text) => Ok(json!({ "message": text })),
+        Response::Failure(text) => bail!(ApiError::daemon_failure(text)),
+        other => bail!(unexpected_response(&other)),
+    }
 }
 
 fn run_cli_add_task(request: AddTaskRequest) -> Result<serde_json::Value> {
+    let envs = request.envs.unwrap_or_default();
     let mut args = vec!["add".to_string(), request.command];
     if let Some(group) = request.group {
         args.push("--group".to_string());
@@ -448,8 +1043,16 @@ fn run_cli_add_task(request: AddTaskRequest) -> Result<serde_json::Value> {
         args.push("--start-immediately".to_string());
         args.push("false".to_string());
     }
+    for dependency in request.dependencies.unwrap_or_default() {
+        args.push("--after".to_string());
+        args.push(dependency.to_string());
+    }
+    if let Some(enqueue_at) = request.enqueue_at.as_deref() {
+        let at = parse_enqueue_at(enqueue_at).map_err(|err| anyhow!(err))?;
+        args.extend(cli_delay_args(at));
+    }
     let refs: Vec<&str> = args.iter().map(|value| value.as_str()).collect();
-    let stdout = run_cli(&refs)?;
+    let stdout = run_cli_with_envs(&refs, &envs)?;
     Ok(json!({ "message": stdout }))
 }
 
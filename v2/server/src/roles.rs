@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use tide::{Middleware, Next, Request, Response};
+
+use crate::auth::Identity;
+use crate::batch::BatchRequest;
+use crate::error::ApiError;
+use crate::{cached_status, peek_json, AppState, BulkSelection};
+
+/// What a user may do. Every role includes the permissions of the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Read the status and logs.
+    Viewer,
+    /// Add, change and control tasks and groups.
+    Operator,
+    /// Everything, including the daemon config and resetting groups.
+    Admin,
+}
+
+/// The role of a user, optionally limited to some pueue groups.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Grant {
+    Role(Role),
+    Scoped {
+        role: Role,
+        /// The groups this user may change. Reading isn't restricted.
+        groups: Option<Vec<String>>,
+    },
+}
+
+impl Grant {
+    pub fn role(&self) -> Role {
+        match self {
+            Grant::Role(role) | Grant::Scoped { role, .. } => *role,
+        }
+    }
+
+    pub fn groups(&self) -> Option<&[String]> {
+        match self {
+            Grant::Role(_) => None,
+            Grant::Scoped { groups, .. } => groups.as_deref(),
+        }
+    }
+}
+
+/// Maps authenticated users to their grants.
+///
+/// ```json
+/// {
+///   "default": "viewer",
+///   "users": {
+///     "alice": "admin",
+///     "intern": { "role": "operator", "groups": ["sandbox"] }
+///   }
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RolePolicy {
+    /// The grant of authenticated users that aren't listed. `None` denies them everything.
+    #[serde(default)]
+    pub default: Option<Grant>,
+    #[serde(default)]
+    pub users: HashMap<String, Grant>,
+}
+
+impl RolePolicy {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read roles file {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Invalid roles file {}", path.display()))
+    }
+
+    pub fn grant(&self, user: &str) -> Option<&Grant> {
+        self.users.get(user).or(self.default.as_ref())
+    }
+}
+
+/// What a request needs: a minimum role and the groups it changes.
+struct Access {
+    role: Role,
+    /// `None` for requests that aren't limited to known groups, e.g. cleaning all groups.
+    groups: Option<Vec<String>>,
+}
+
+impl Access {
+    fn read() -> Self {
+        Access {
+            role: Role::Viewer,
+            groups: Some(Vec::new()),
+        }
+    }
+
+    fn global(role: Role) -> Self {
+        Access { role, groups: None }
+    }
+
+    fn groups(role: Role, groups: Vec<String>) -> Self {
+        Access {
+            role,
+            groups: Some(groups),
+        }
+    }
+}
+
+/// Checks the grant of the authenticated user against every request.
+///
+/// Requests without an [`Identity`] are let through, as there's nobody to check
+/// permissions for if authentication is disabled.
+pub(crate) struct Authorize {
+    policy: RolePolicy,
+}
+
+impl Authorize {
+    pub(crate) fn new(policy: RolePolicy) -> Self {
+        Self { policy }
+    }
+}
+
+#[async_trait]
+impl Middleware<AppState> for Authorize {
+    async fn handle(&self, mut req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+        let Some(identity) = req.ext::<Identity>().cloned() else {
+            return Ok(next.run(req).await);
+        };
+        let Some(grant) = self.policy.grant(&identity.user).cloned() else {
+            return Ok(forbidden(&format!("User {} has no role", identity.user)));
+        };
+
+        // Requests to `/d/:name/...` are checked against the routes and tasks of that daemon.
+        let path = req.url().path().to_string();
+        let (state, route) = match path
+            .strip_prefix("/d/")
+            .and_then(|rest| rest.split_once('/'))
+        {
+            Some((name, route)) => {
+                let state = req
+                    .state()
+                    .daemons
+                    .iter()
+                    .find(|(daemon, _)| daemon == name)
+                    .map(|(_, state)| state.clone());
+                match state {
+                    Some(state) => (state, format!("/{route}")),
+                    // Unknown daemons are answered with a 404 by the router.
+                    None => return Ok(next.run(req).await),
+                }
+            }
+            None => (req.state().clone(), path),
+        };
+
+        let access = required_access(&mut req, &state, &route).await?;
+        if grant.role() < access.role {
+            return Ok(forbidden(&format!(
+                "This requires the {:?} role",
+                access.role
+            )));
+        }
+        if let Some(allowed) = grant.groups() {
+            match access.groups.as_ref() {
+                Some(groups) if groups.iter().all(|group| allowed.contains(group)) => {}
+                Some(_) => return Ok(forbidden("Not allowed for this group")),
+                None if access.role == Role::Viewer => {}
+                None => return Ok(forbidden("Not allowed for users limited to groups")),
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+async fn required_access(
+    req: &mut Request<AppState>,
+    state: &AppState,
+    route: &str,
+) -> tide::Result<Access> {
+    let method = req.method();
+    let segments: Vec<&str> = route.trim_matches('/').split('/').collect();
+    let access = match (method, segments.as_slice()) {
+        (_, ["health"]) => Access::read(),
+        (tide::http::Method::Get, ["status" | "events" | "daemons"]) => Access::read(),
+        (tide::http::Method::Get, ["logs", ..]) => Access::read(),
+        (tide::http::Method::Get, ["audit"]) => Access::global(Role::Admin),
+        (tide::http::Method::Get, ["config", "callback"]) => Access::global(Role::Operator),
+        (_, ["config", "callback"]) => Access::global(Role::Admin),
+        (_, ["task", id, ..]) => {
+            let groups = task_groups(state, &[id.parse().unwrap_or(usize::MAX)]).await;
+            Access {
+                role: Role::Operator,
+                groups,
+            }
+        }
+        (_, ["tasks"]) => {
+            #[derive(Deserialize)]
+            struct Body {
+                group: Option<String>,
+            }
+            let body: Option<Body> = peek_json(req).await?;
+            let group = body
+                .and_then(|body| body.group)
+                .unwrap_or_else(|| "default".to_string());
+            Access::groups(Role::Operator, vec![group])
+        }
+        (_, ["tasks", "actions"]) => {
+            #[derive(Deserialize)]
+            struct Body {
+                selection: BulkSelection,
+            }
+            let groups = match peek_json::<Body>(req).await?.map(|body| body.selection) {
+                Some(BulkSelection::Ids(ids)) => task_groups(state, &ids).await,
+                Some(BulkSelection::Group(group)) => Some(vec![group]),
+                Some(BulkSelection::All) | None => None,
+            };
+            Access {
+                role: Role::Operator,
+                groups,
+            }
+        }
+        (_, ["tasks", "batch"]) => {
+            let groups = match peek_json::<BatchRequest>(req).await? {
+                Some(body) => body.expand(state).ok().map(|tasks| {
+                    tasks
+                        .into_iter()
+                        .map(|task| task.group.unwrap_or_else(|| "default".to_string()))
+                        .collect()
+                }),
+                None => None,
+            };
+            Access {
+                role: Role::Operator,
+                groups,
+            }
+        }
+        (_, ["queue", "switch"]) => {
+            #[derive(Deserialize)]
+            struct Body {
+                task_id_1: usize,
+                task_id_2: usize,
+            }
+            let groups = match peek_json::<Body>(req).await? {
+                Some(body) => task_groups(state, &[body.task_id_1, body.task_id_2]).await,
+                None => None,
+            };
+            Access {
+                role: Role::Operator,
+                groups,
+            }
+        }
+        (_, ["groups"]) => {
+            #[derive(Deserialize)]
+            struct Body {
+                action: String,
+                name: String,
+            }
+            match peek_json::<Body>(req).await? {
+                Some(body) if body.action == "reset" => Access::groups(Role::Admin, vec![body.name]),
+                Some(body) => Access::groups(Role::Operator, vec![body.name]),
+                None => Access::global(Role::Operator),
+            }
+        }
+        (_, ["clean"]) => {
+            #[derive(Deserialize)]
+            struct Body {
+                group: Option<String>,
+            }
+            let group = peek_json::<Body>(req).await?.and_then(|body| body.group);
+            Access {
+                role: Role::Operator,
+                groups: group.map(|group| vec![group]),
+            }
+        }
+        (_, ["templates", name, "run"]) => {
+            #[derive(Deserialize)]
+            struct Body {
+                group: Option<String>,
+            }
+            let group = peek_json::<Body>(req)
+                .await?
+                .and_then(|body| body.group)
+                .or_else(|| {
+                    let store = state.templates.as_ref()?;
+                    store.get(name)?.group
+                })
+                .unwrap_or_else(|| "default".to_string());
+            Access::groups(Role::Operator, vec![group])
+        }
+        (tide::http::Method::Get, ["templates", ..]) => Access::read(),
+        (_, ["templates", _]) => Access::global(Role::Operator),
+        (tide::http::Method::Get, _) => Access::global(Role::Viewer),
+        _ => Access::global(Role::Admin),
+    };
+    Ok(access)
+}
+
+/// The groups of the given tasks. `None` if any of them is unknown.
+async fn task_groups(state: &AppState, ids: &[usize]) -> Option<Vec<String>> {
+    let (entry, _) = cached_status(state).await.ok()?;
+    ids.iter()
+        .map(|id| entry.payload.tasks.get(id).map(|task| task.group.clone()))
+        .collect()
+}
+
+fn forbidden(error: &str) -> Response {
+    ApiError::Forbidden(error.to_string()).response()
+}
@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tide::{Request, StatusCode};
+
+use crate::error::ApiError;
+use crate::{
+    error_response, json_response, mask_envs, prepare_add_task, AddTaskRequest, AppState,
+    MASKED_VALUE,
+};
+
+/// A reusable task. Its command, path, label and env values may contain
+/// `{{name}}` placeholders, which are filled in when the template is run.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TaskTemplate {
+    pub command: String,
+    pub group: Option<String>,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub envs: BTreeMap<String, String>,
+    pub priority: Option<i32>,
+    pub label: Option<String>,
+    /// Values for placeholders that may be left out when running the template.
+    #[serde(default)]
+    pub defaults: BTreeMap<String, String>,
+}
+
+impl TaskTemplate {
+    /// All placeholders of the template.
+    pub fn parameters(&self) -> BTreeSet<String> {
+        let texts = [Some(&self.command), self.path.as_ref(), self.label.as_ref()];
+        texts
+            .into_iter()
+            .flatten()
+            .chain(self.envs.values())
+            .flat_map(|text| placeholders(text))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.command.trim().is_empty() {
+            return Err(ApiError::BadRequest("Missing command".to_string()));
+        }
+        let parameters = self.parameters();
+        let unused: Vec<&str> = self
+            .defaults
+            .keys()
+            .filter(|name| !parameters.contains(*name))
+            .map(String::as_str)
+            .collect();
+        if !unused.is_empty() {
+            return Err(ApiError::BadRequest(format!(
+                "Defaults for unknown parameters: {}",
+                unused.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fill in the placeholders. Every parameter needs a value, either in `params`
+    /// or in the template's defaults, and `params` may not contain anything else.
+    pub fn render(&self, params: &BTreeMap<String, String>) -> Result<AddTaskRequest, ApiError> {
+        let parameters = self.parameters();
+        let unknown: Vec<&str> = params
+            .keys()
+            .filter(|name| !parameters.contains(*name))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(ApiError::BadRequest(format!(
+                "Unknown parameters: {}",
+                unknown.join(", ")
+            )));
+        }
+        let mut values = self.defaults.clone();
+        values.extend(params.clone());
+        let missing: Vec<&str> = parameters
+            .iter()
+            .filter(|name| !values.contains_key(*name))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(ApiError::BadRequest(format!(
+                "Missing parameters: {}",
+                missing.join(", ")
+            )));
+        }
+
+        let envs = self
+            .envs
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), expand(value, &values)?)))
+            .collect::<Result<HashMap<_, _>, ApiError>>()?;
+        Ok(AddTaskRequest {
+            command: expand(&self.command, &values)?,
+            group: self.group.clone(),
+            start_immediately: None,
+            stashed: None,
+            priority: self.priority,
+            label: self
+                .label
+                .as_deref()
+                .map(|label| expand(label, &values))
+                .transpose()?,
+            path: self
+                .path
+                .as_deref()
+                .map(|path| expand(path, &values))
+                .transpose()?,
+            envs: (!envs.is_empty()).then_some(envs),
+            dependencies: None,
+            enqueue_at: None,
+        })
+    }
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+/// Split `text` into text and placeholders. Braces that don't enclose a valid
+/// name, like in `docker ps --format '{{.ID}}'`, are kept as they are.
+fn parse(text: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut rest = text;
+    while let Some(open) = rest.find("{{") {
+        let offset = text.len() - rest.len();
+        let placeholder = rest[open + 2..].find("}}").and_then(|close| {
+            let name = rest[open + 2..open + 2 + close].trim();
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|char| char.is_ascii_alphanumeric() || char == '_' || char == '-');
+            valid.then_some((name, open + 2 + close + 2))
+        });
+        match placeholder {
+            Some((name, end)) => {
+                parts.push(Part::Text(&text[start..offset + open]));
+                parts.push(Part::Placeholder(name));
+                start = offset + end;
+                rest = &rest[end..];
+            }
+            None => rest = &rest[open + 2..],
+        }
+    }
+    parts.push(Part::Text(&text[start..]));
+    parts
+}
+
+/// The names of the placeholders in `text`.
+pub(crate) fn placeholders(text: &str) -> BTreeSet<String> {
+    parse(text)
+        .into_iter()
+        .filter_map(|part| match part {
+            Part::Placeholder(name) => Some(name.to_string()),
+            Part::Text(_) => None,
+        })
+        .collect()
+}
+
+/// Replace the `{{name}}` placeholders of `text` with their values.
+pub(crate) fn expand(text: &str, values: &BTreeMap<String, String>) -> Result<String, ApiError> {
+    let mut expanded = String::with_capacity(text.len());
+    for part in parse(text) {
+        match part {
+            Part::Text(text) => expanded.push_str(text),
+            Part::Placeholder(name) => match values.get(name) {
+                Some(value) => expanded.push_str(value),
+                None => {
+                    return Err(ApiError::BadRequest(format!("Missing parameters: {name}")));
+                }
+            },
+        }
+    }
+    Ok(expanded)
+}
+
+/// Parameter values may be given as strings, numbers or booleans.
+pub(crate) fn parameter_value(name: &str, value: &Value) -> Result<String, ApiError> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Number(number) => Ok(number.to_string()),
+        Value::Bool(flag) => Ok(flag.to_string()),
+        _ => Err(ApiError::BadRequest(format!(
+            "Parameter {name} has to be a string, number or boolean"
+        ))),
+    }
+}
+
+fn parameter_values(params: &BTreeMap<String, Value>) -> Result<BTreeMap<String, String>, ApiError> {
+    params
+        .iter()
+        .map(|(name, value)| Ok((name.clone(), parameter_value(name, value)?)))
+        .collect()
+}
+
+/// Named templates, persisted as a JSON object in a file.
+pub struct TemplateStore {
+    path: PathBuf,
+    templates: Mutex<BTreeMap<String, TaskTemplate>>,
+}
+
+impl TemplateStore {
+    /// Load the templates from `path`, which doesn't have to exist yet.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let templates = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Invalid templates file {}", path.display()))?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Failed to read templates {}", path.display()))
+            }
+        };
+        Ok(Self {
+            path,
+            templates: Mutex::new(templates),
+        })
+    }
+
+    /// Uses `path` if set, `PUEUE_WEBUI_TEMPLATES` otherwise. `None` disables templates.
+    pub fn from_env(path: Option<PathBuf>) -> Result<Option<Self>> {
+        let path = path.or_else(|| {
+            std::env::var("PUEUE_WEBUI_TEMPLATES")
+                .ok()
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        });
+        path.map(Self::open).transpose()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn list(&self) -> BTreeMap<String, TaskTemplate> {
+        self.lock().clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<TaskTemplate> {
+        self.lock().get(name).cloned()
+    }
+
+    /// Create or replace a template. Returns `true` if it's new.
+    pub fn put(&self, name: &str, template: TaskTemplate) -> Result<bool> {
+        let mut templates = self.lock();
+        let mut updated = templates.clone();
+        let created = updated.insert(name.to_string(), template).is_none();
+        self.save(&updated)?;
+        *templates = updated;
+        Ok(created)
+    }
+
+    /// Returns `false` if there's no such template.
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        let mut templates = self.lock();
+        if !templates.contains_key(name) {
+            return Ok(false);
+        }
+        let mut updated = templates.clone();
+        updated.remove(name);
+        self.save(&updated)?;
+        *templates = updated;
+        Ok(true)
+    }
+
+    /// Write to a temporary file first, so that a crash never leaves a truncated file.
+    fn save(&self, templates: &BTreeMap<String, TaskTemplate>) -> Result<()> {
+        if let Some(parent) = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_vec_pretty(templates)?)
+            .with_context(|| format!("Failed to write templates {}", temporary.display()))?;
+        std::fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Failed to write templates {}", self.path.display()))?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, TaskTemplate>> {
+        self.templates.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// A template with its name and placeholders, as returned by the API.
+///
+/// Viewers may read templates as well, so the values of secret env variables are masked.
+fn view(name: &str, template: &TaskTemplate) -> Value {
+    let mut masked = template.clone();
+    mask_envs(&mut masked.envs);
+    let mut value = json!(masked);
+    value["name"] = json!(name);
+    value["parameters"] = json!(template.parameters());
+    value
+}
+
+fn store(req: &Request<AppState>) -> Result<Arc<TemplateStore>, ApiError> {
+    req.state()
+        .templates
+        .clone()
+        .ok_or_else(|| ApiError::NotFound("Templates are disabled".to_string()))
+}
+
+fn template_name(req: &Request<AppState>) -> Result<String, ApiError> {
+    let name = req.param("name").unwrap_or_default().trim().to_string();
+    if name.is_empty() {
+        return Err(ApiError::BadRequest("Missing template name".to_string()));
+    }
+    Ok(name)
+}
+
+fn not_found(name: &str) -> ApiError {
+    ApiError::NotFound(format!("Template {name} doesn't exist"))
+}
+
+/// A stored template of the daemon's app.
+pub(crate) fn lookup(state: &AppState, name: &str) -> Result<TaskTemplate, ApiError> {
+    let store = state
+        .templates
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Templates are disabled".to_string()))?;
+    store.get(name).ok_or_else(|| not_found(name))
+}
+
+/// `GET /templates`
+pub(crate) async fn list_handler(req: Request<AppState>) -> tide::Result {
+    let store = store(&req)?;
+    let templates: Vec<Value> = store
+        .list()
+        .iter()
+        .map(|(name, template)| view(name, template))
+        .collect();
+    json_response(
+        StatusCode::Ok,
+        json!({
+            "ok": true,
+            "result": templates,
+        }),
+    )
+}
+
+/// `GET /templates/:name`
+pub(crate) async fn get_handler(req: Request<AppState>) -> tide::Result {
+    let store = store(&req)?;
+    let name = template_name(&req)?;
+    match store.get(&name) {
+        Some(template) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "result": view(&name, &template),
+            }),
+        ),
+        None => error_response(not_found(&name)),
+    }
+}
+
+/// `PUT /templates/:name`
+pub(crate) async fn put_handler(mut req: Request<AppState>) -> tide::Result {
+    let store = store(&req)?;
+    let name = template_name(&req)?;
+    let mut template: TaskTemplate = req
+        .body_json()
+        .await
+        .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body"))?;
+    // A template that's sent back as it was read keeps its masked secrets.
+    if let Some(previous) = store.get(&name) {
+        for (key, value) in template.envs.iter_mut() {
+            match previous.envs.get(key) {
+                Some(secret) if value == MASKED_VALUE => *value = secret.clone(),
+                _ => {}
+            }
+        }
+    }
+    template.validate()?;
+
+    let saved = template.clone();
+    let result = async_std::task::spawn_blocking({
+        let name = name.clone();
+        move || store.put(&name, saved)
+    })
+    .await;
+    match result {
+        Ok(created) => json_response(
+            if created {
+                StatusCode::Created
+            } else {
+                StatusCode::Ok
+            },
+            json!({
+                "ok": true,
+                "result": view(&name, &template),
+            }),
+        ),
+        Err(error) => error_response(error),
+    }
+}
+
+/// `DELETE /templates/:name`
+pub(crate) async fn delete_handler(req: Request<AppState>) -> tide::Result {
+    let store = store(&req)?;
+    let name = template_name(&req)?;
+    let removed = async_std::task::spawn_blocking({
+        let name = name.clone();
+        move || store.remove(&name)
+    })
+    .await;
+    match removed {
+        Ok(true) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "result": { "message": format!("Template {name} removed") },
+            }),
+        ),
+        Ok(false) => error_response(not_found(&name)),
+        Err(error) => error_response(error),
+    }
+}
+
+/// The body of `POST /templates/:name/run`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RunTemplateRequest {
+    #[serde(default)]
+    pub params: BTreeMap<String, Value>,
+    /// Overrides of the template's settings.
+    pub group: Option<String>,
+    pub priority: Option<i32>,
+    pub label: Option<String>,
+    pub start_immediately: Option<bool>,
+    pub stashed: Option<bool>,
+    pub dependencies: Option<Vec<usize>>,
+    pub enqueue_at: Option<String>,
+}
+
+/// `POST /templates/:name/run`
+pub(crate) async fn run_handler(mut req: Request<AppState>) -> tide::Result {
+    let name = template_name(&req)?;
+    let body: RunTemplateRequest = req
+        .body_json()
+        .await
+        .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, "Invalid JSON body"))?;
+    let template = lookup(req.state(), &name)?;
+
+    let mut task = template.render(&parameter_values(&body.params)?)?;
+    task.group = body.group.or(task.group);
+    task.priority = body.priority.or(task.priority);
+    task.label = body.label.or(task.label);
+    task.start_immediately = body.start_immediately;
+    task.stashed = body.stashed;
+    task.dependencies = body.dependencies;
+    task.enqueue_at = body.enqueue_at;
+    prepare_add_task(req.state(), &mut task).await?;
+
+    match req.state().backend.add_task(task.clone()).await {
+        Ok(result) => json_response(
+            StatusCode::Ok,
+            json!({
+                "ok": true,
+                "command": task.command,
+                "result": result,
+            }),
+        ),
+        Err(error) => error_response(error),
+    }
+}
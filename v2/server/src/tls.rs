@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_dup::{Arc as DupArc, Mutex as DupMutex};
+use async_std::net::TcpListener;
+use futures_rustls::TlsAcceptor;
+use log::{debug, info};
+use pueue_lib::settings::Shared;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+/// Where to find the certificate and key to serve HTTPS with.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Use the certificate and key the pueue daemon uses for its TLS socket.
+    pub from_daemon: bool,
+}
+
+impl TlsOptions {
+    /// Fill everything that hasn't been set explicitly from the environment.
+    pub fn with_env_defaults(mut self) -> Self {
+        let env = |key: &str| std::env::var(key).ok().filter(|value| !value.is_empty());
+        self.cert = self
+            .cert
+            .or_else(|| env("PUEUE_WEBUI_TLS_CERT").map(PathBuf::from));
+        self.key = self
+            .key
+            .or_else(|| env("PUEUE_WEBUI_TLS_KEY").map(PathBuf::from));
+        self.from_daemon = self.from_daemon
+            || env("PUEUE_WEBUI_TLS_FROM_DAEMON").is_some_and(|value| value != "0");
+        self
+    }
+
+    /// The certificate and key paths, or `None` to serve plain HTTP.
+    pub fn paths(&self, daemon: &Shared) -> Result<Option<(PathBuf, PathBuf)>> {
+        match (self.cert.clone(), self.key.clone()) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) if self.from_daemon => {
+                Ok(Some((daemon.daemon_cert(), daemon.daemon_key())))
+            }
+            (None, None) => Ok(None),
+            _ => bail!("TLS needs both a certificate and a key"),
+        }
+    }
+}
+
+/// Build the rustls config from PEM files. The certificate file may contain a whole chain.
+pub fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| anyhow!("Failed to read certificate {}: {err:?}", cert.display()))?;
+    if certs.is_empty() {
+        bail!("No certificate found in {}", cert.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|err| anyhow!("Failed to read private key {}: {err:?}", key.display()))?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("Failed to set up TLS")?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")
+}
+
+/// How long to wait before accepting again, e.g. when running out of file descriptors.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Clients that don't finish the TLS handshake within this time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve `app` over HTTPS. Like [`tide::Server::listen`], this runs until the process ends.
+pub async fn listen<State>(app: tide::Server<State>, host: &str, config: ServerConfig) -> Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(host)
+        .await
+        .with_context(|| format!("Failed to bind {host}"))?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    info!("Server listening on https://{}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                debug!("Failed to accept connection: {error}");
+                async_std::task::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        async_std::task::spawn(async move {
+            let handshake = async_std::future::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+            let stream = match handshake.await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(error)) => {
+                    debug!("TLS handshake with {peer} failed: {error}");
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {peer} timed out");
+                    return;
+                }
+            };
+            // async-h1 needs to clone the stream for reading and writing.
+            let stream = DupArc::new(DupMutex::new(stream));
+            let result = async_h1::accept(stream, |mut req| {
+                let app = app.clone();
+                async move {
+                    req.set_peer_addr(Some(peer));
+                    req.url_mut()
+                        .set_scheme("https")
+                        .map_err(|_| tide::Error::from_str(500, "Failed to set URL scheme"))?;
+                    app.respond(req).await
+                }
+            })
+            .await;
+            if let Err(error) = result {
+                debug!("Connection with {peer} failed: {error}");
+            }
+        });
+    }
+}